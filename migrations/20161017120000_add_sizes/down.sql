@@ -0,0 +1,35 @@
+CREATE TABLE snapshots_old (
+	id		INTEGER PRIMARY KEY,
+	tag		INTEGER,
+	family_id	INTEGER,
+        snapshot_id	INTEGER,
+        msg		BLOB,
+	hash		BLOB,
+	tree_ref	BLOB
+);
+INSERT INTO snapshots_old SELECT id, tag, family_id, snapshot_id, msg, hash, tree_ref
+	FROM snapshots;
+DROP TABLE snapshots;
+ALTER TABLE snapshots_old RENAME TO snapshots;
+
+CREATE TABLE keys_old (
+	id             INTEGER PRIMARY KEY,
+        parent         INTEGER,
+        name           BLOB,
+
+	created        Integer,
+        modified       Integer,
+        accessed       Integer,
+
+	permissions    Integer,
+	user_id        Integer,
+	group_id       Integer,
+
+        hash           BLOB,
+        persistent_ref BLOB
+);
+INSERT INTO keys_old SELECT id, parent, name, created, modified, accessed, permissions, user_id,
+	group_id, hash, persistent_ref FROM keys;
+DROP TABLE keys;
+ALTER TABLE keys_old RENAME TO keys;
+CREATE UNIQUE INDEX Keys_UniqueParentName ON keys(parent, name);
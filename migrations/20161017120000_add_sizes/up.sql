@@ -0,0 +1,4 @@
+ALTER TABLE snapshots ADD COLUMN created INTEGER;
+ALTER TABLE snapshots ADD COLUMN size INTEGER;
+
+ALTER TABLE keys ADD COLUMN data_length INTEGER;
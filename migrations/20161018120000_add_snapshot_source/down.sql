@@ -0,0 +1,15 @@
+CREATE TABLE snapshots_old (
+	id		INTEGER PRIMARY KEY,
+	tag		INTEGER,
+	family_id	INTEGER,
+        snapshot_id	INTEGER,
+        msg		BLOB,
+	hash		BLOB,
+	tree_ref	BLOB,
+	created		INTEGER,
+	size		INTEGER
+);
+INSERT INTO snapshots_old SELECT id, tag, family_id, snapshot_id, msg, hash, tree_ref, created,
+	size FROM snapshots;
+DROP TABLE snapshots;
+ALTER TABLE snapshots_old RENAME TO snapshots;
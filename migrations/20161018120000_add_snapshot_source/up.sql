@@ -0,0 +1,2 @@
+ALTER TABLE snapshots ADD COLUMN host BLOB;
+ALTER TABLE snapshots ADD COLUMN path BLOB;
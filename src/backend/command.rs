@@ -0,0 +1,143 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::io::Write;
+use std::process::{Command, ExitStatus, Stdio};
+use std::str;
+
+use backend::StoreBackend;
+
+/// Exit status of the retrieve command when the object does not exist.
+pub const NOT_FOUND_STATUS: i32 = 4;
+
+/// Shell commands for each backend operation. Every command is run with `sh -c`, with the hex
+/// encoded object name as `$1`.
+#[derive(Clone, Debug)]
+pub struct Commands {
+    /// Store the object read from stdin.
+    pub store: String,
+    /// Write the object to stdout, or exit with `NOT_FOUND_STATUS` if it does not exist.
+    pub retrieve: String,
+    pub delete: String,
+    /// Make stored objects durable. Optional.
+    pub flush: Option<String>,
+    /// Write the names of all objects to stdout, one per line. Optional.
+    pub list: Option<String>,
+}
+
+/// A backend that leaves the actual storage to external programs.
+pub struct CommandBackend {
+    commands: Commands,
+}
+
+fn failed(cmd: &str, status: ExitStatus) -> String {
+    format!("Command '{}' failed: {}", cmd, status)
+}
+
+impl CommandBackend {
+    pub fn new(commands: Commands) -> CommandBackend {
+        CommandBackend { commands: commands }
+    }
+
+    fn command(cmd: &str, name: Option<&[u8]>) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd).arg("hat");
+        if let Some(name) = name {
+            command.arg(name.to_hex());
+        }
+        command.stderr(Stdio::inherit());
+        command
+    }
+
+    fn run(&self, cmd: &str, name: Option<&[u8]>) -> Result<(), String> {
+        let status = try!(CommandBackend::command(cmd, name)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|e| e.to_string()));
+        if status.success() {
+            Ok(())
+        } else {
+            Err(failed(cmd, status))
+        }
+    }
+}
+
+impl StoreBackend for CommandBackend {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        let cmd = &self.commands.store;
+        let mut child = try!(CommandBackend::command(cmd, Some(name))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| e.to_string()));
+
+        // Close stdin before waiting, so the command sees the end of the data.
+        let written = child.stdin.take().unwrap().write_all(data);
+        let status = try!(child.wait().map_err(|e| e.to_string()));
+        if !status.success() {
+            return Err(failed(cmd, status));
+        }
+        written.map_err(|e| e.to_string())
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let cmd = &self.commands.retrieve;
+        let output = try!(CommandBackend::command(cmd, Some(name))
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| e.to_string()));
+        if output.status.success() {
+            Ok(Some(output.stdout))
+        } else if output.status.code() == Some(NOT_FOUND_STATUS) {
+            Ok(None)
+        } else {
+            Err(failed(cmd, output.status))
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.run(&self.commands.delete, Some(name))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        match self.commands.flush {
+            Some(ref cmd) => self.run(cmd, None),
+            None => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        let cmd = match self.commands.list {
+            Some(ref cmd) => cmd,
+            None => return Err("No list command configured".to_owned()),
+        };
+        let output = try!(CommandBackend::command(cmd, None)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| e.to_string()));
+        if !output.status.success() {
+            return Err(failed(cmd, output.status));
+        }
+
+        let listing = try!(str::from_utf8(&output.stdout[..]).map_err(|e| e.to_string()));
+        let mut names = Vec::new();
+        for line in listing.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            names.push(try!(line.from_hex()
+                .map_err(|e| format!("Invalid object name '{}': {}", line, e))));
+        }
+        Ok(names)
+    }
+}
@@ -32,4 +32,8 @@ impl StoreBackend for DevNullBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        Ok(Vec::new())
+    }
 }
@@ -0,0 +1,177 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+use backend::StoreBackend;
+
+
+/// Backend operations that faults can be injected into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Op {
+    Store,
+    /// Both `retrieve` and `retrieve_range`.
+    Retrieve,
+    Delete,
+    Flush,
+    List,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Fail with an error, without touching the wrapped backend.
+    Error,
+    /// Return the object with one of its bytes flipped. Only affects retrieves.
+    Corrupt,
+    /// Claim that the object does not exist. Only affects retrieves.
+    Missing,
+    /// Store the first half of the object, then fail. Only affects stores.
+    PartialWrite,
+}
+
+struct Faults {
+    calls: BTreeMap<Op, u64>,
+    // Faults by operation and call number, or for every call.
+    pending: Vec<(Op, Option<u64>, Fault)>,
+}
+
+/// A backend for tests that makes chosen operations of another backend go wrong.
+pub struct FaultyBackend<B> {
+    backend: B,
+    faults: Mutex<Faults>,
+}
+
+impl<B: StoreBackend> FaultyBackend<B> {
+    pub fn new(backend: B) -> FaultyBackend<B> {
+        FaultyBackend {
+            backend: backend,
+            faults: Mutex::new(Faults {
+                calls: BTreeMap::new(),
+                pending: Vec::new(),
+            }),
+        }
+    }
+
+    /// The wrapped backend, to inspect or modify without faults.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn lock(&self) -> MutexGuard<Faults> {
+        self.faults.lock().expect("Faults were poisoned")
+    }
+
+    /// Inject `fault` into the `nth` next call of `op`, counting from 1.
+    pub fn inject(&self, op: Op, nth: u64, fault: Fault) {
+        let mut faults = self.lock();
+        let call = faults.calls.get(&op).cloned().unwrap_or(0) + nth;
+        faults.pending.push((op, Some(call), fault));
+    }
+
+    /// Inject `fault` into every call of `op` from now on.
+    pub fn inject_all(&self, op: Op, fault: Fault) {
+        self.lock().pending.push((op, None, fault));
+    }
+
+    /// Remove all faults that have not happened yet.
+    pub fn clear(&self) {
+        self.lock().pending.clear();
+    }
+
+    /// Number of calls of `op` so far.
+    pub fn calls(&self, op: Op) -> u64 {
+        self.lock().calls.get(&op).cloned().unwrap_or(0)
+    }
+
+    /// Count a call of `op` and return the fault to inject into it, if any.
+    fn next(&self, op: Op) -> Option<Fault> {
+        let mut faults = self.lock();
+        let call = {
+            let calls = faults.calls.entry(op).or_insert(0);
+            *calls += 1;
+            *calls
+        };
+        faults.pending
+            .iter()
+            .find(|&&(o, nth, _)| o == op && nth.map_or(true, |n| n == call))
+            .map(|&(_, _, fault)| fault)
+    }
+
+    fn read<F>(&self, name: &[u8], f: F) -> Result<Option<Vec<u8>>, String>
+        where F: FnOnce() -> Result<Option<Vec<u8>>, String>
+    {
+        match self.next(Op::Retrieve) {
+            Some(Fault::Error) => Err(format!("Injected retrieve error for {:?}", name)),
+            Some(Fault::Missing) => Ok(None),
+            Some(Fault::Corrupt) => {
+                Ok(try!(f()).map(|mut data| {
+                    if !data.is_empty() {
+                        let middle = data.len() / 2;
+                        data[middle] ^= 0xff;
+                    }
+                    data
+                }))
+            }
+            Some(Fault::PartialWrite) | None => f(),
+        }
+    }
+
+    fn fail(&self, op: Op) -> Result<(), String> {
+        match self.next(op) {
+            Some(Fault::Error) => Err(format!("Injected {:?} error", op)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for FaultyBackend<B> {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        match self.next(Op::Store) {
+            Some(Fault::Error) => Err(format!("Injected store error for {:?}", name)),
+            Some(Fault::PartialWrite) => {
+                try!(self.backend.store(name, &data[..data.len() / 2]));
+                Err(format!("Injected partial write for {:?}", name))
+            }
+            _ => self.backend.store(name, data),
+        }
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.read(name, || self.backend.retrieve(name))
+    }
+
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        self.read(name, || self.backend.retrieve_range(name, offset, length))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        try!(self.fail(Op::Delete));
+        self.backend.delete(name)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        try!(self.fail(Op::Flush));
+        self.backend.flush()
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        try!(self.fail(Op::List));
+        self.backend.list()
+    }
+}
@@ -12,49 +12,95 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use rustc_serialize::hex::ToHex;
-use std::collections::BTreeMap;
+use rustc_serialize::hex::{FromHex, ToHex};
 use std::fs;
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 
-use backend::StoreBackend;
+use backend::{StoreBackend, slice_range};
+use util::LruCache;
 
+/// Default size of the read cache in bytes.
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Stores each blob in its own file. Files are spread over two levels of directories named by
+/// the first bytes of the (hex encoded) name, e.g. `ab/cd/abcdef...`, to keep directories small.
 pub struct FileBackend {
     root: PathBuf,
-    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
-    max_cache_size: usize,
+    read_cache: Mutex<LruCache<Vec<u8>>>,
+}
+
+fn sync_dir(dir: &Path) -> Result<(), String> {
+    fs::File::open(dir).and_then(|dir| dir.sync_all()).map_err(|e| e.to_string())
 }
 
 impl FileBackend {
     pub fn new(root: PathBuf) -> FileBackend {
+        FileBackend::with_cache_size(root, DEFAULT_CACHE_SIZE)
+    }
+
+    /// A backend that caches up to `cache_size` bytes of recently read blobs.
+    pub fn with_cache_size(root: PathBuf, cache_size: usize) -> FileBackend {
         FileBackend {
             root: root,
-            read_cache: Mutex::new(BTreeMap::new()),
-            max_cache_size: 10,
+            read_cache: Mutex::new(LruCache::new(cache_size)),
         }
     }
 
-    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
-        match self.read_cache.lock() {
-            Err(e) => Some(Err(e.to_string())),
-            Ok(cache) => cache.get(name).map(|v| v.clone()),
+    fn shard_dir(&self, name: &[u8]) -> PathBuf {
+        let hex = name.to_hex();
+        let mut dir = self.root.clone();
+        if hex.len() >= 4 {
+            dir.push(&hex[0..2]);
+            dir.push(&hex[2..4]);
         }
+        dir
     }
 
-    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
-        // Read key:
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
+    fn path(&self, name: &[u8]) -> PathBuf {
+        let mut path = self.shard_dir(name);
+        path.push(&name.to_hex());
+        path
+    }
 
-        match fs::File::open(&path) {
-            Err(_) => Ok(None),
-            Ok(mut fd) => {
+    /// Where older versions kept the file: directly in the root.
+    fn flat_path(&self, name: &[u8]) -> PathBuf {
+        let mut path = self.root.clone();
+        path.push(&name.to_hex());
+        path
+    }
+
+    /// The file of a blob in the old flat layout, if there is one apart from the sharded one.
+    fn flat_file(&self, name: &[u8]) -> Option<PathBuf> {
+        let flat = self.flat_path(name);
+        if flat != self.path(name) && flat.is_file() {
+            Some(flat)
+        } else {
+            None
+        }
+    }
+
+    /// Open the file of a blob. Files in the old flat layout are read where they are; reads
+    /// never move files, and they only move into place when the blob is stored again.
+    fn open(&self, name: &[u8]) -> io::Result<Option<fs::File>> {
+        match fs::File::open(&self.path(name)) {
+            Ok(fd) => return Ok(Some(fd)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        match self.flat_file(name) {
+            Some(flat) => Ok(Some(try!(fs::File::open(&flat)))),
+            None => Ok(None),
+        }
+    }
+
+    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self.open(name) {
+            Err(e) => Err(e.to_string()),
+            Ok(None) => Ok(None),
+            Ok(Some(mut fd)) => {
                 let mut buf = Vec::new();
                 match fd.read_to_end(&mut buf) {
                     Ok(_) => Ok(Some(buf)),
@@ -64,67 +110,159 @@ impl FileBackend {
         }
     }
 
-    fn guarded_cache_delete(&self, name: &[u8]) {
-        self.read_cache.lock().unwrap().remove(name);
+    fn get_range(&self,
+                 name: &[u8],
+                 offset: usize,
+                 length: usize)
+                 -> Result<Option<Vec<u8>>, String> {
+        match self.open(name) {
+            Err(e) => Err(e.to_string()),
+            Ok(None) => Ok(None),
+            Ok(Some(mut fd)) => {
+                let mut buf = vec![0; length];
+                if let Err(e) = fd.seek(SeekFrom::Start(offset as u64)) {
+                    return Err(e.to_string());
+                }
+                match fd.read_exact(&mut buf) {
+                    Ok(()) => Ok(Some(buf)),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+        }
     }
 
-    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
-        let mut cache = self.read_cache.lock().unwrap();
-        if cache.len() >= self.max_cache_size {
-            cache.clear();
+    /// Add the names of blob files in `dir` to `names`, descending `depth` levels of shards.
+    fn list_dir(dir: &Path, depth: usize, names: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(_) => continue,
+            };
+            if try!(entry.file_type()).is_dir() {
+                if depth > 0 && file_name.len() == 2 {
+                    try!(FileBackend::list_dir(&entry.path(), depth - 1, names));
+                }
+            } else if let Ok(name) = file_name.from_hex() {
+                // Unfinished stores are left as "<name>.tmp", which is not valid hex.
+                names.push(name);
+            }
         }
-        cache.insert(name, result);
+        Ok(())
+    }
+
+    fn guarded_cache_get(&self, name: &[u8]) -> Option<Vec<u8>> {
+        self.read_cache.lock().unwrap().get(&name.to_vec()).map(|v| v.clone())
+    }
+
+    fn guarded_cache_delete(&self, name: &[u8]) {
+        self.read_cache.lock().unwrap().remove(&name.to_vec());
+    }
+
+    fn guarded_cache_put(&self, name: Vec<u8>, data: Vec<u8>) {
+        self.read_cache.lock().unwrap().put(name, data);
     }
 }
 
 impl StoreBackend for FileBackend {
     fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
-        let mut path = self.root.clone();
-        path.push(&name.to_hex());
+        self.guarded_cache_delete(name);
+
+        let dir = self.shard_dir(name);
+        let new_dir = !dir.is_dir();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            return Err(e.to_string());
+        }
+
+        let path = self.path(name);
+        let mut tmp_path = dir.clone();
+        tmp_path.push(format!("{}.tmp", name.to_hex()));
 
-        let mut file = match fs::File::create(&path) {
-            Err(e) => return Err(e.to_string()),
-            Ok(f) => f,
+        // Write to a temporary file and rename it into place once it is on disk, so that a crash
+        // never leaves a partially written file under the final name.
+        let written = fs::File::create(&tmp_path).and_then(|mut file| {
+            try!(file.write_all(data));
+            file.sync_all()
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.to_string());
+        }
+        if let Err(e) = fs::rename(&tmp_path, &path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.to_string());
+        }
+        // Do not leave an old copy behind in the flat layout.
+        let removed_flat = match self.flat_file(name) {
+            Some(flat) => fs::remove_file(&flat).is_ok(),
+            None => false,
         };
 
-        match file.write_all(data) {
-            Err(e) => Err(e.to_string()),
-            Ok(()) => Ok(()),
+        // The rename is only durable once the directory is, and so are new directories.
+        try!(sync_dir(&dir));
+        if new_dir {
+            try!(sync_dir(dir.parent().unwrap()));
+        }
+        if new_dir || removed_flat {
+            try!(sync_dir(&self.root));
         }
+        Ok(())
     }
 
     fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
-        // Check for key in cache:
-        let value_opt = self.guarded_cache_get(name);
-        if let Some(r) = value_opt {
-            return r;
+        if let Some(data) = self.guarded_cache_get(name) {
+            return Ok(Some(data));
         }
 
-        let res = self.get(name);
-
-        // Update cache to contain key:
-        self.guarded_cache_put(name.to_vec(), res.clone());
+        let res = try!(self.get(name));
+        if let Some(ref data) = res {
+            self.guarded_cache_put(name.to_vec(), data.clone());
+        }
+        Ok(res)
+    }
 
-        res
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        // Use the cached blob if we have it, but do not cache partial reads:
+        match self.guarded_cache_get(name) {
+            Some(data) => slice_range(&data[..], offset, length).map(Some),
+            None => self.get_range(name, offset, length),
+        }
     }
 
     fn delete(&self, name: &[u8]) -> Result<(), String> {
-        let name = name.to_vec();
-        self.guarded_cache_delete(&name);
+        self.guarded_cache_delete(name);
 
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
+        // Files in the flat layout of older versions are only moved when stored again.
+        let removed_flat = match self.flat_file(name) {
+            Some(flat) => {
+                try!(fs::remove_file(&flat).map_err(|e| e.to_string()));
+                try!(sync_dir(&self.root));
+                true
+            }
+            None => false,
         };
-
-        match fs::remove_file(&path) {
-            Ok(_) => Ok(()),
+        match fs::remove_file(&self.path(name)) {
+            Ok(_) => sync_dir(&self.shard_dir(name)),
+            Err(ref e) if removed_flat && e.kind() == io::ErrorKind::NotFound => Ok(()),
             Err(e) => Err(e.to_string()),
         }
     }
 
     fn flush(&self) -> Result<(), String> {
-        Ok(())
+        // Stores and deletes are synced one by one, which leaves only the root directory.
+        sync_dir(&self.root)
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut names = Vec::new();
+        try!(FileBackend::list_dir(&self.root, 2, &mut names).map_err(|e| e.to_string()));
+        // A blob can be in both layouts if removing its flat copy failed.
+        names.sort();
+        names.dedup();
+        Ok(names)
     }
 }
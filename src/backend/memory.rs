@@ -15,7 +15,7 @@
 use std::collections::BTreeMap;
 use std::sync::Mutex;
 
-use backend::StoreBackend;
+use backend::{StoreBackend, slice_range};
 
 pub struct MemoryBackend {
     files: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
@@ -58,6 +58,22 @@ impl StoreBackend for MemoryBackend {
         self.guarded_retrieve(name)
     }
 
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        match self.files.lock() {
+            Err(e) => Err(e.to_string()),
+            Ok(map) => {
+                match map.get(name) {
+                    Some(data) => slice_range(&data[..], offset, length).map(Some),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String> {
         self.guarded_delete(name)
     }
@@ -65,4 +81,11 @@ impl StoreBackend for MemoryBackend {
     fn flush(&self) -> Result<(), String> {
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        match self.files.lock() {
+            Err(e) => Err(e.to_string()),
+            Ok(map) => Ok(map.keys().cloned().collect()),
+        }
+    }
 }
@@ -0,0 +1,423 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+
+use backend::StoreBackend;
+
+
+/// Stores every object in several backends (replicas). Writes succeed when at least `quorum`
+/// replicas accept them, and reads are served by the first replica that has the object.
+///
+/// Replicas that missed a write, or turned out not to have an object when read, are recorded in
+/// a journal, so that `repair` can copy the object to them later. So are replicas that failed to
+/// delete an object, so that `repair` deletes it instead of copying it back. Reads skip replicas
+/// with such a pending repair, as their copy may be stale.
+pub struct MirrorBackend<B> {
+    replicas: Vec<B>,
+    quorum: usize,
+    journal: Option<PathBuf>,
+    pending: Mutex<Journal>,
+}
+
+/// Repairs still to be done, as (replica index, object name).
+#[derive(Default)]
+struct Journal {
+    /// Objects to copy to a replica.
+    missing: BTreeSet<(usize, Vec<u8>)>,
+    /// Objects to delete from a replica.
+    deleted: BTreeSet<(usize, Vec<u8>)>,
+}
+
+impl Journal {
+    fn is_pending(&self, replica: usize, name: &[u8]) -> bool {
+        let key = (replica, name.to_vec());
+        self.missing.contains(&key) || self.deleted.contains(&key)
+    }
+
+    /// Record a repair, replacing any earlier one of the same object on the same replica.
+    fn insert(&mut self, replica: usize, name: &[u8], deleted: bool) -> bool {
+        let key = (replica, name.to_vec());
+        if deleted {
+            self.missing.remove(&key);
+            self.deleted.insert(key)
+        } else {
+            self.deleted.remove(&key);
+            self.missing.insert(key)
+        }
+    }
+
+    /// Forget the repairs of an object on a replica. Returns whether there were any.
+    fn forget(&mut self, replica: usize, name: &[u8]) -> bool {
+        let key = (replica, name.to_vec());
+        let missing = self.missing.remove(&key);
+        self.deleted.remove(&key) || missing
+    }
+}
+
+fn read_journal(path: &PathBuf) -> Result<Journal, String> {
+    let mut text = String::new();
+    match fs::File::open(path) {
+        Ok(mut fd) => try!(fd.read_to_string(&mut text).map_err(|e| e.to_string())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Journal::default()),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    // Later entries replace earlier ones, as they are appended in order.
+    let mut journal = Journal::default();
+    for line in text.lines().filter(|l| !l.is_empty()) {
+        let mut fields = line.split(' ');
+        let replica = fields.next().and_then(|r| r.parse::<usize>().ok());
+        let name = fields.next().and_then(|n| n.from_hex().ok());
+        let deleted = match fields.next() {
+            None => Some(false),
+            Some(DELETED) => Some(true),
+            Some(_) => None,
+        };
+        match (replica, name, deleted) {
+            (Some(replica), Some(name), Some(deleted)) => {
+                journal.insert(replica, &name[..], deleted);
+            }
+            _ => return Err(format!("Invalid mirror journal entry: '{}'", line)),
+        }
+    }
+    Ok(journal)
+}
+
+/// Marks journal entries of objects to delete; other entries are objects to copy.
+const DELETED: &'static str = "deleted";
+
+fn journal_line(replica: usize, name: &[u8], deleted: bool) -> String {
+    if deleted {
+        format!("{} {} {}\n", replica, name.to_hex(), DELETED)
+    } else {
+        format!("{} {}\n", replica, name.to_hex())
+    }
+}
+
+impl<B: StoreBackend> MirrorBackend<B> {
+    /// Mirror objects to all `replicas`. The journal of pending repairs is kept in the file
+    /// `journal`, if given, and only in memory otherwise.
+    pub fn new(replicas: Vec<B>,
+               quorum: usize,
+               journal: Option<PathBuf>)
+               -> Result<MirrorBackend<B>, String> {
+        if quorum == 0 || quorum > replicas.len() {
+            return Err(format!("Quorum must be between 1 and {}", replicas.len()));
+        }
+        let pending = match journal {
+            Some(ref path) => try!(read_journal(path)),
+            None => Journal::default(),
+        };
+        Ok(MirrorBackend {
+            replicas: replicas,
+            quorum: quorum,
+            journal: journal,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<Journal> {
+        self.pending.lock().expect("Mirror journal was poisoned")
+    }
+
+    pub fn replicas(&self) -> &[B] {
+        &self.replicas[..]
+    }
+
+    /// Objects known to be missing from a replica, by replica index.
+    pub fn missing(&self) -> Vec<(usize, Vec<u8>)> {
+        self.lock().missing.iter().cloned().collect()
+    }
+
+    /// Objects that a replica failed to delete, by replica index.
+    pub fn deleted(&self) -> Vec<(usize, Vec<u8>)> {
+        self.lock().deleted.iter().cloned().collect()
+    }
+
+    /// Record that `name` is missing from `replica`, or with `deleted`, that it is still there.
+    fn record(&self, replica: usize, name: &[u8], deleted: bool) -> Result<(), String> {
+        let mut pending = self.lock();
+        if !pending.insert(replica, name, deleted) {
+            return Ok(());
+        }
+        if let Some(ref path) = self.journal {
+            if let Some(dir) = path.parent() {
+                try!(fs::create_dir_all(dir).map_err(|e| e.to_string()));
+            }
+            let mut fd = try!(fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| e.to_string()));
+            try!(fd.write_all(journal_line(replica, name, deleted).as_bytes())
+                .and_then(|()| fd.sync_all())
+                .map_err(|e| e.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Forget the repairs of `name` on the replicas that are up to date with it again.
+    fn forget(&self, replicas: &[usize], name: &[u8]) -> Result<(), String> {
+        let mut pending = self.lock();
+        let mut changed = false;
+        for &i in replicas.iter() {
+            changed = pending.forget(i, name) || changed;
+        }
+        if changed {
+            try!(self.write_journal(&pending));
+        }
+        Ok(())
+    }
+
+    /// Replace the journal file with the current set of pending repairs.
+    fn write_journal(&self, pending: &Journal) -> Result<(), String> {
+        let path = match self.journal {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for &(replica, ref name) in pending.missing.iter() {
+            text.push_str(&journal_line(replica, &name[..], false));
+        }
+        for &(replica, ref name) in pending.deleted.iter() {
+            text.push_str(&journal_line(replica, &name[..], true));
+        }
+        let tmp = path.with_extension("tmp");
+        try!(fs::File::create(&tmp)
+            .and_then(|mut fd| fd.write_all(text.as_bytes()).and_then(|()| fd.sync_all()))
+            .map_err(|e| e.to_string()));
+        fs::rename(&tmp, path).map_err(|e| e.to_string())
+    }
+
+    /// Fail unless enough replicas succeeded. `errors` holds the failed replicas.
+    fn check_quorum(&self, what: &str, errors: &[(usize, String)]) -> Result<(), String> {
+        let succeeded = self.replicas.len() - errors.len();
+        if succeeded >= self.quorum {
+            return Ok(());
+        }
+        let reasons: Vec<String> =
+            errors.iter().map(|&(i, ref e)| format!("replica {}: {}", i, e)).collect();
+        Err(format!("{} succeeded on {} of {} replicas (quorum is {}): {}",
+                    what,
+                    succeeded,
+                    self.replicas.len(),
+                    self.quorum,
+                    reasons.join("; ")))
+    }
+
+    /// Read from the first replica that has the object, recording the ones that do not.
+    /// Replicas with a pending repair of the object are skipped, as they may hold an old copy.
+    fn read<F>(&self, name: &[u8], f: F) -> Result<Option<Vec<u8>>, String>
+        where F: Fn(&B) -> Result<Option<Vec<u8>>, String>
+    {
+        let stale: Vec<usize> = {
+            let pending = self.lock();
+            (0..self.replicas.len()).filter(|&i| pending.is_pending(i, name)).collect()
+        };
+        let mut absent = Vec::new();
+        let mut errors = Vec::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if stale.contains(&i) {
+                continue;
+            }
+            match f(replica) {
+                Ok(Some(data)) => {
+                    for j in absent.into_iter() {
+                        try!(self.record(j, name, false));
+                    }
+                    return Ok(Some(data));
+                }
+                Ok(None) => absent.push(i),
+                Err(e) => errors.push(format!("replica {}: {}", i, e)),
+            }
+        }
+        if errors.is_empty() {
+            Ok(None)
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    /// Copy missing objects to the replicas that lack them, and delete objects from the
+    /// replicas that failed to delete them. With `full`, all replicas are listed first to find
+    /// objects missing from them, also those that were never recorded. Returns the number of
+    /// objects repaired and the number of repairs still pending.
+    pub fn repair(&self, full: bool) -> Result<(usize, usize), String> {
+        if full {
+            let mut listings = Vec::new();
+            for replica in self.replicas.iter() {
+                let names: HashSet<Vec<u8>> = try!(replica.list()).into_iter().collect();
+                listings.push(names);
+            }
+            // Objects still to be deleted somewhere are not copied back.
+            let deleted: HashSet<Vec<u8>> =
+                self.deleted().into_iter().map(|(_, name)| name).collect();
+            let all: BTreeSet<&Vec<u8>> = listings.iter()
+                .flat_map(|l| l.iter())
+                .filter(|n| !deleted.contains(*n))
+                .collect();
+            for (i, names) in listings.iter().enumerate() {
+                for name in all.iter().filter(|n| !names.contains(**n)) {
+                    try!(self.record(i, &name[..], false));
+                }
+            }
+        }
+
+        let mut repaired = 0;
+        let mut remaining = Journal::default();
+        let mut touched = BTreeSet::new();
+        for (i, name) in self.deleted().into_iter() {
+            match self.replicas[i].delete(&name[..]) {
+                Ok(()) => {
+                    repaired += 1;
+                    touched.insert(i);
+                }
+                // Deleting an object that is gone already can fail; that is fine.
+                Err(_) if self.replicas[i].retrieve(&name[..]) == Ok(None) => (),
+                Err(e) => {
+                    warn!("Could not delete from replica {}: {}", i, e);
+                    remaining.insert(i, &name[..], true);
+                }
+            }
+        }
+        let missing = self.missing();
+        for &(i, ref name) in missing.iter() {
+            let mut data = None;
+            let mut unavailable = false;
+            for (j, replica) in self.replicas.iter().enumerate().filter(|&(j, _)| j != i) {
+                // Only copy from replicas that are up to date with the object.
+                if missing.contains(&(j, name.clone())) {
+                    unavailable = true;
+                    continue;
+                }
+                match replica.retrieve(&name[..]) {
+                    Ok(Some(d)) => {
+                        data = Some(d);
+                        break;
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        warn!("Could not read from replica {}: {}", j, e);
+                        unavailable = true;
+                    }
+                }
+            }
+            match data {
+                Some(data) => {
+                    match self.replicas[i].store(&name[..], &data[..]) {
+                        Ok(()) => {
+                            repaired += 1;
+                            touched.insert(i);
+                        }
+                        Err(e) => {
+                            warn!("Could not repair replica {}: {}", i, e);
+                            remaining.insert(i, &name[..], false);
+                        }
+                    }
+                }
+                // Try again later, unless the object is gone from all other replicas.
+                None if unavailable => {
+                    remaining.insert(i, &name[..], false);
+                }
+                None => (),
+            }
+        }
+        for i in touched.into_iter() {
+            try!(self.replicas[i].flush());
+        }
+
+        let count = remaining.missing.len() + remaining.deleted.len();
+        try!(self.write_journal(&remaining));
+        *self.lock() = remaining;
+        Ok((repaired, count))
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for MirrorBackend<B> {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if let Err(e) = replica.store(name, data) {
+                errors.push((i, e));
+            }
+        }
+        try!(self.check_quorum("Store", &errors[..]));
+        for &(i, _) in errors.iter() {
+            try!(self.record(i, name, false));
+        }
+        // The other replicas have the latest version now.
+        let stored: Vec<usize> =
+            (0..self.replicas.len()).filter(|i| errors.iter().all(|&(j, _)| j != *i)).collect();
+        self.forget(&stored[..], name)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.read(name, |replica| replica.retrieve(name))
+    }
+
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        self.read(name, |replica| replica.retrieve_range(name, offset, length))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if let Err(e) = replica.delete(name) {
+                errors.push((i, e));
+            }
+        }
+        try!(self.check_quorum("Delete", &errors[..]));
+
+        // Replicas that still have the object must delete it on repair, and not spread it again.
+        for &(i, _) in errors.iter() {
+            try!(self.record(i, name, true));
+        }
+        let deleted: Vec<usize> =
+            (0..self.replicas.len()).filter(|i| errors.iter().all(|&(j, _)| j != *i)).collect();
+        self.forget(&deleted[..], name)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            if let Err(e) = replica.flush() {
+                errors.push((i, e));
+            }
+        }
+        self.check_quorum("Flush", &errors[..])
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut names = BTreeSet::new();
+        let mut errors = Vec::new();
+        for (i, replica) in self.replicas.iter().enumerate() {
+            match replica.list() {
+                Ok(listed) => names.extend(listed.into_iter()),
+                Err(e) => errors.push((i, e)),
+            }
+        }
+        try!(self.check_quorum("List", &errors[..]));
+        Ok(names.into_iter().collect())
+    }
+}
@@ -12,17 +12,89 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod command;
 mod devnull;
+#[cfg(test)]
+mod faulty;
 mod file;
 mod memory;
+mod mirror;
+mod retry;
+mod s3;
+#[cfg(test)]
+pub mod tests;
 
+pub use self::command::{CommandBackend, Commands, NOT_FOUND_STATUS};
 pub use self::devnull::DevNullBackend;
-pub use self::file::FileBackend;
+#[cfg(test)]
+pub use self::faulty::{Fault, FaultyBackend, Op};
+pub use self::file::{DEFAULT_CACHE_SIZE, FileBackend};
 pub use self::memory::MemoryBackend;
+pub use self::mirror::MirrorBackend;
+pub use self::retry::{DEFAULT_ATTEMPTS, DEFAULT_DELAY_MS, RetryingBackend};
+pub use self::s3::{S3Backend, S3Config};
+
+fn slice_range(data: &[u8], offset: usize, length: usize) -> Result<Vec<u8>, String> {
+    if offset + length > data.len() {
+        return Err(format!("Range {}+{} is outside object of length {}",
+                           offset,
+                           length,
+                           data.len()));
+    }
+    Ok(data[offset..offset + length].to_vec())
+}
 
 pub trait StoreBackend: Sync + Send + 'static {
     fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String>;
     fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    /// Retrieve `length` bytes starting at `offset` of a stored object. Backends that can read
+    /// part of an object should override this, as the default retrieves the whole object.
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        match try!(self.retrieve(name)) {
+            Some(data) => slice_range(&data[..], offset, length).map(Some),
+            None => Ok(None),
+        }
+    }
+
     fn delete(&self, name: &[u8]) -> Result<(), String>;
     fn flush(&self) -> Result<(), String>;
+
+    /// Names of all stored objects, in no particular order.
+    fn list(&self) -> Result<Vec<Vec<u8>>, String>;
+}
+
+/// Lets the backend be chosen at runtime.
+impl StoreBackend for Box<StoreBackend> {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        (**self).store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve(name)
+    }
+
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        (**self).retrieve_range(name, offset, length)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        (**self).delete(name)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        (**self).flush()
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        (**self).list()
+    }
 }
@@ -0,0 +1,112 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rand::{self, Rng};
+use rustc_serialize::hex::ToHex;
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use backend::StoreBackend;
+
+
+pub const DEFAULT_ATTEMPTS: u32 = 5;
+pub const DEFAULT_DELAY_MS: u64 = 200;
+pub const MAX_DELAY_MS: u64 = 30 * 1000;
+
+/// A backend that retries failed operations of another backend. The delay between attempts
+/// doubles every time, and is randomized so that many clients do not retry in lockstep.
+pub struct RetryingBackend<B> {
+    backend: B,
+    attempts: u32,
+    delay_ms: u64,
+}
+
+impl<B: StoreBackend> RetryingBackend<B> {
+    pub fn new(backend: B) -> RetryingBackend<B> {
+        RetryingBackend::with_attempts(backend, DEFAULT_ATTEMPTS, DEFAULT_DELAY_MS)
+    }
+
+    /// Try every operation up to `attempts` times, waiting about `delay_ms` milliseconds before
+    /// the first retry.
+    pub fn with_attempts(backend: B, attempts: u32, delay_ms: u64) -> RetryingBackend<B> {
+        assert!(attempts > 0);
+        RetryingBackend {
+            backend: backend,
+            attempts: attempts,
+            delay_ms: delay_ms,
+        }
+    }
+
+    /// Milliseconds to wait after the given (zero-based) failed attempt.
+    fn delay(&self, attempt: u32) -> u64 {
+        let max = cmp::min(MAX_DELAY_MS, self.delay_ms.saturating_mul(1 << cmp::min(attempt, 16)));
+        // Full jitter on the upper half: wait between max/2 and max.
+        max / 2 + rand::thread_rng().gen_range(0, max / 2 + 1)
+    }
+
+    fn retry<T, F>(&self, what: &str, name: Option<&[u8]>, mut f: F) -> Result<T, String>
+        where F: FnMut(&B) -> Result<T, String>
+    {
+        let mut attempt = 0;
+        loop {
+            match f(&self.backend) {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    attempt += 1;
+                    if attempt >= self.attempts {
+                        return Err(format!("{} failed after {} attempts: {}", what, attempt, e));
+                    }
+                    let delay = self.delay(attempt - 1);
+                    warn!("{} of '{}' failed, retrying in {}ms: {}",
+                          what,
+                          name.map(|n| n.to_hex()).unwrap_or_default(),
+                          delay,
+                          e);
+                    thread::sleep(Duration::from_millis(delay));
+                }
+            }
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for RetryingBackend<B> {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        self.retry("Store", Some(name), |b| b.store(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.retry("Retrieve", Some(name), |b| b.retrieve(name))
+    }
+
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        self.retry("Retrieve", Some(name), |b| b.retrieve_range(name, offset, length))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        self.retry("Delete", Some(name), |b| b.delete(name))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.retry("Flush", None, |b| b.flush())
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.retry("List", None, |b| b.list())
+    }
+}
@@ -0,0 +1,474 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend for S3 compatible object storage (AWS S3, MinIO, ...).
+//!
+//! Requests are signed with AWS signature version 4 and use path-style addressing
+//! (`endpoint/bucket/key`), which every S3 compatible server supports.
+
+use hyper::Client;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use rustc_serialize::hex::{FromHex, ToHex};
+use sodiumoxide::crypto::hash::sha256;
+use std::char;
+use std::io::Read;
+use time;
+
+use backend::StoreBackend;
+
+/// Objects larger than this are uploaded in parts of this size. S3 requires parts (except the
+/// last) to be at least 5 MiB.
+pub const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    /// Scheme and host of the server, e.g. `https://s3.amazonaws.com` or `http://localhost:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `backups/`.
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub part_size: usize,
+}
+
+impl S3Config {
+    pub fn new(endpoint: String,
+               bucket: String,
+               access_key: String,
+               secret_key: String)
+               -> S3Config {
+        S3Config {
+            endpoint: endpoint.trim_right_matches('/').to_owned(),
+            region: "us-east-1".to_owned(),
+            bucket: bucket,
+            prefix: String::new(),
+            access_key: access_key,
+            secret_key: secret_key,
+            part_size: DEFAULT_PART_SIZE,
+        }
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut key = if key.len() > BLOCK_SIZE {
+        sha256::hash(key).0.to_vec()
+    } else {
+        key.to_vec()
+    };
+    key.resize(BLOCK_SIZE, 0);
+
+    let mut inner: Vec<u8> = key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256::hash(&inner[..]).0[..]);
+    sha256::hash(&outer[..]).0.to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    sha256::hash(data).0.to_hex()
+}
+
+/// Percent-encode everything but unreserved characters (and '/' in paths), as SigV4 requires.
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            b'/' if !encode_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+pub struct Signer {
+    pub access_key: String,
+    pub secret_key: String,
+    pub region: String,
+}
+
+impl Signer {
+    /// The `Authorization` header for a request. `headers` must have lowercase names, be sorted
+    /// by name and include `host`, `x-amz-content-sha256` and `x-amz-date`.
+    pub fn authorization(&self,
+                         method: &str,
+                         uri: &str,
+                         query: &str,
+                         headers: &[(String, String)],
+                         payload_hash: &str,
+                         amz_date: &str)
+                         -> String {
+        let signed_headers = headers.iter()
+            .map(|&(ref name, _)| &name[..])
+            .collect::<Vec<_>>()
+            .join(";");
+        let mut canonical = format!("{}\n{}\n{}\n", method, uri, query);
+        for &(ref name, ref value) in headers.iter() {
+            canonical.push_str(&format!("{}:{}\n", name, value.trim()));
+        }
+        canonical.push_str(&format!("\n{}\n{}", signed_headers, payload_hash));
+
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date,
+                                     scope,
+                                     sha256_hex(canonical.as_bytes()));
+
+        let key = format!("AWS4{}", self.secret_key);
+        let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key[..], self.region.as_bytes());
+        let key = hmac_sha256(&key[..], b"s3");
+        let key = hmac_sha256(&key[..], b"aws4_request");
+        let signature = hmac_sha256(&key[..], string_to_sign.as_bytes()).to_hex();
+
+        format!("AWS4-HMAC-SHA256 Credential={}/{},SignedHeaders={},Signature={}",
+                self.access_key,
+                scope,
+                signed_headers,
+                signature)
+    }
+}
+
+struct Response {
+    status: StatusCode,
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+/// Contents of all `<name>` elements. Good enough for the simple responses of S3.
+fn xml_elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(i) = rest.find(&open[..]) {
+        rest = &rest[i + open.len()..];
+        match rest.find(&close[..]) {
+            Some(len) => {
+                found.push(&rest[..len]);
+                rest = &rest[len + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    xml_elements(xml, name).into_iter().next()
+}
+
+/// Replace the entities S3 escapes text with by the characters they stand for.
+pub fn xml_unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let code = match entity {
+            "amp" => Some('&' as u32),
+            "lt" => Some('<' as u32),
+            "gt" => Some('>' as u32),
+            "apos" => Some('\'' as u32),
+            "quot" => Some('"' as u32),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok(),
+            _ if entity.starts_with('#') => entity[1..].parse().ok(),
+            _ => None,
+        };
+        match code.and_then(char::from_u32) {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                // Not an entity; keep the text as it is.
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The names of the objects in a listing, without `prefix`. Keys outside `prefix`, or that are
+/// not hex names written by us, are skipped.
+pub fn listed_names(xml: &str, prefix: &str) -> Vec<Vec<u8>> {
+    xml_elements(xml, "Key")
+        .into_iter()
+        .map(xml_unescape)
+        .filter(|key| key.starts_with(prefix))
+        .filter_map(|key| key[prefix.len()..].from_hex().ok())
+        .collect()
+}
+
+pub struct S3Backend {
+    config: S3Config,
+    signer: Signer,
+    host: String,
+    client: Client,
+}
+
+impl S3Backend {
+    pub fn new(config: S3Config) -> S3Backend {
+        let host = {
+            let without_scheme = match config.endpoint.find("://") {
+                Some(i) => &config.endpoint[i + 3..],
+                None => &config.endpoint[..],
+            };
+            without_scheme.split('/').next().unwrap().to_owned()
+        };
+        S3Backend {
+            signer: Signer {
+                access_key: config.access_key.clone(),
+                secret_key: config.secret_key.clone(),
+                region: config.region.clone(),
+            },
+            host: host,
+            client: Client::new(),
+            config: config,
+        }
+    }
+
+    /// Object key for a blob name; the same hex scheme as `FileBackend` uses for file names.
+    fn key(&self, name: &[u8]) -> String {
+        format!("{}{}", self.config.prefix, name.to_hex())
+    }
+
+    fn request(&self,
+               method: Method,
+               key: &str,
+               query: &[(&str, &str)],
+               range: Option<String>,
+               body: &[u8])
+               -> Result<Response, String> {
+        let uri = format!("/{}/{}", uri_encode(&self.config.bucket, true), uri_encode(key, false));
+
+        let mut query: Vec<(String, String)> = query.iter()
+            .map(|&(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let query = query.iter()
+            .map(|&(ref k, ref v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let payload_hash = sha256_hex(body);
+        let amz_date = time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap();
+
+        let mut signed = vec![("host".to_owned(), self.host.clone()),
+                              ("x-amz-content-sha256".to_owned(), payload_hash.clone()),
+                              ("x-amz-date".to_owned(), amz_date.clone())];
+        if let Some(range) = range {
+            signed.push(("range".to_owned(), range));
+        }
+        signed.sort();
+        let authorization = self.signer.authorization(&method.to_string(),
+                                                      &uri,
+                                                      &query,
+                                                      &signed[..],
+                                                      &payload_hash,
+                                                      &amz_date);
+
+        let mut headers = Headers::new();
+        for (name, value) in signed.into_iter() {
+            headers.set_raw(name, vec![value.into_bytes()]);
+        }
+        headers.set_raw("authorization", vec![authorization.into_bytes()]);
+
+        let url = if query.is_empty() {
+            format!("{}{}", self.config.endpoint, uri)
+        } else {
+            format!("{}{}?{}", self.config.endpoint, uri, query)
+        };
+        let mut res = try!(self.client
+            .request(method, &url[..])
+            .headers(headers)
+            .body(body)
+            .send()
+            .map_err(|e| e.to_string()));
+
+        // Always read the full response, so the connection can be reused.
+        let mut body = Vec::new();
+        try!(res.read_to_end(&mut body).map_err(|e| e.to_string()));
+        Ok(Response {
+            status: res.status,
+            headers: res.headers.clone(),
+            body: body,
+        })
+    }
+
+    fn expect_success(res: Response, what: &str) -> Result<Response, String> {
+        if res.status.is_success() {
+            Ok(res)
+        } else {
+            Err(format!("S3 {} failed: {} {}",
+                        what,
+                        res.status,
+                        String::from_utf8_lossy(&res.body[..])))
+        }
+    }
+
+    /// Like `request`, but fails on any status other than success.
+    fn call(&self,
+            what: &str,
+            method: Method,
+            key: &str,
+            query: &[(&str, &str)],
+            body: &[u8])
+            -> Result<Response, String> {
+        let res = try!(self.request(method, key, query, None, body));
+        S3Backend::expect_success(res, what)
+    }
+
+    fn store_multipart(&self, key: &str, data: &[u8]) -> Result<(), String> {
+        let res = try!(self.call("multipart start", Method::Post, key, &[("uploads", "")], &[]));
+        let upload_id = {
+            let xml = String::from_utf8_lossy(&res.body[..]);
+            match xml_element(&xml, "UploadId") {
+                Some(id) => id.to_owned(),
+                None => return Err("S3 multipart start returned no upload id".to_owned()),
+            }
+        };
+
+        match self.upload_parts(key, &upload_id, data) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Do not leave the parts behind; they are billed until aborted.
+                let _ = self.request(Method::Delete, key, &[("uploadId", &upload_id)], None, &[]);
+                Err(e)
+            }
+        }
+    }
+
+    fn upload_parts(&self, key: &str, upload_id: &str, data: &[u8]) -> Result<(), String> {
+        let mut complete = "<CompleteMultipartUpload>".to_owned();
+        for (i, part) in data.chunks(self.config.part_size).enumerate() {
+            let number = (i + 1).to_string();
+            let query = [("partNumber", &number[..]), ("uploadId", upload_id)];
+            let res = try!(self.call("part upload", Method::Put, key, &query, part));
+            let etag = match res.headers.get_raw("etag") {
+                Some(values) if !values.is_empty() => String::from_utf8_lossy(&values[0]),
+                _ => return Err("S3 part upload returned no ETag".to_owned()),
+            };
+            complete.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                                       number,
+                                       etag));
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+
+        let res = try!(self.call("multipart completion",
+                                 Method::Post,
+                                 key,
+                                 &[("uploadId", upload_id)],
+                                 complete.as_bytes()));
+        // Completion can fail after the server has answered 200 OK.
+        let xml = String::from_utf8_lossy(&res.body[..]);
+        match xml_element(&xml, "Code") {
+            Some(code) => Err(format!("S3 multipart completion failed: {}", code)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StoreBackend for S3Backend {
+    fn store(&self, name: &[u8], data: &[u8]) -> Result<(), String> {
+        let key = self.key(name);
+        if data.len() > self.config.part_size {
+            return self.store_multipart(&key, data);
+        }
+        try!(self.call("upload", Method::Put, &key, &[], data));
+        Ok(())
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let res = try!(self.request(Method::Get, &self.key(name), &[], None, &[]));
+        if res.status == StatusCode::NotFound {
+            return Ok(None);
+        }
+        Ok(Some(try!(S3Backend::expect_success(res, "download")).body))
+    }
+
+    fn retrieve_range(&self,
+                      name: &[u8],
+                      offset: usize,
+                      length: usize)
+                      -> Result<Option<Vec<u8>>, String> {
+        if length == 0 {
+            let res = try!(self.request(Method::Head, &self.key(name), &[], None, &[]));
+            if res.status == StatusCode::NotFound {
+                return Ok(None);
+            }
+            try!(S3Backend::expect_success(res, "lookup"));
+            return Ok(Some(Vec::new()));
+        }
+
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let res = try!(self.request(Method::Get, &self.key(name), &[], Some(range), &[]));
+        if res.status == StatusCode::NotFound {
+            return Ok(None);
+        }
+        let body = try!(S3Backend::expect_success(res, "ranged download")).body;
+        if body.len() != length {
+            return Err(format!("S3 returned {} bytes for a range of {}", body.len(), length));
+        }
+        Ok(Some(body))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), String> {
+        try!(self.call("delete", Method::Delete, &self.key(name), &[], &[]));
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        // Objects are durable once their upload has been acknowledged.
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut names = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let xml = {
+                let mut query = vec![("list-type", "2"), ("prefix", &self.config.prefix[..])];
+                if let Some(ref token) = token {
+                    query.push(("continuation-token", &token[..]));
+                }
+                let res = try!(self.call("listing", Method::Get, "", &query[..], &[]));
+                String::from_utf8_lossy(&res.body[..]).into_owned()
+            };
+            // Skip objects not written by us, e.g. other files under the same prefix.
+            names.extend(listed_names(&xml, &self.config.prefix[..]));
+            match xml_element(&xml, "NextContinuationToken") {
+                Some(next) if xml_element(&xml, "IsTruncated") == Some("true") => {
+                    token = Some(xml_unescape(next))
+                }
+                _ => return Ok(names),
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use capnp;
 use root_capnp;
+use zstd;
 
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -8,12 +9,69 @@ pub enum Kind {
     TreeLeaf = 2,
 }
 
+/// How the bytes of a chunk were compressed before being sealed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Codec {
+    None,
+    Zstd,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "none" => Some(Codec::None),
+            "zstd" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// Compress `data`. Returns `None` if this does not save at least 1/32 of the size, which is
+    /// the case for data that is already compressed.
+    pub fn encode(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match *self {
+            Codec::None => None,
+            Codec::Zstd => {
+                match zstd::encode_all(data, ZSTD_LEVEL) {
+                    Ok(out) => {
+                        if out.len() + data.len() / 32 < data.len() {
+                            Some(out)
+                        } else {
+                            None
+                        }
+                    }
+                    Err(_) => None,
+                }
+            }
+        }
+    }
+
+    pub fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match *self {
+            Codec::None => Ok(data),
+            Codec::Zstd => {
+                zstd::decode_all(&data[..])
+                    .map_err(|e| format!("Could not decompress chunk: {}", e))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ChunkRef {
     pub blob_id: Vec<u8>,
     pub offset: usize,
     pub length: usize,
     pub kind: Kind,
+    pub codec: Codec,
 }
 
 impl ChunkRef {
@@ -47,6 +105,10 @@ impl ChunkRef {
             Kind::TreeLeaf => msg.init_kind().set_tree_leaf(()),
             Kind::TreeBranch => msg.init_kind().set_tree_branch(()),
         }
+        match self.codec {
+            Codec::None => msg.init_compression().set_none(()),
+            Codec::Zstd => msg.init_compression().set_zstd(()),
+        }
     }
 
     pub fn read_msg(msg: &root_capnp::chunk_ref::Reader) -> Result<ChunkRef, capnp::Error> {
@@ -58,6 +120,10 @@ impl ChunkRef {
                 root_capnp::chunk_ref::kind::TreeBranch(()) => Kind::TreeBranch,
                 root_capnp::chunk_ref::kind::TreeLeaf(()) => Kind::TreeLeaf,
             },
+            codec: match try!(msg.get_compression().which()) {
+                root_capnp::chunk_ref::compression::None(()) => Codec::None,
+                root_capnp::chunk_ref::compression::Zstd(()) => Codec::Zstd,
+            },
         })
     }
 }
@@ -127,11 +193,9 @@ impl Blob {
         assert!(meta_meta_len < 255);
         footer.push(meta_meta_len as u8);
 
+        // The footer is found from the end of the blob, so there is no need to pad the blob to
+        // `max_size`. Blobs written by older versions were padded with zeros before the footer.
         assert!(out.len() + footer.len() <= self.max_size);
-        let left = self.max_size - out.len() - footer.len();
-        if left > 0 {
-            out.append(&mut vec![0; left]);
-        }
         out.append(&mut footer);
 
         // Everything has been reset. We are ready to go again.
@@ -164,24 +228,35 @@ impl Blob {
             return Ok(Vec::new());
         }
 
+        // Blobs that were only partially written can end in anything.
+        let invalid = || capnp::Error::failed("Invalid blob footer".to_owned());
+
         let meta_meta_len = *bytes.last().unwrap() as usize;
         let mut pos = bytes.len() - 1;
-        assert!(pos >= meta_meta_len);
+        if pos < meta_meta_len {
+            return Err(invalid());
+        }
         pos -= meta_meta_len;
 
         let meta_len = try!(Blob::len_from(&mut &bytes[pos..pos + meta_meta_len])) as usize;
-        assert!(pos >= meta_len);
+        if pos < meta_len {
+            return Err(invalid());
+        }
         pos -= meta_len;
 
         let footer_len = try!(Blob::len_from(&mut &bytes[pos..pos + meta_len])) as usize;
-        assert!(pos >= footer_len);
+        if pos < footer_len {
+            return Err(invalid());
+        }
         pos -= footer_len;
 
         let mut footer = &bytes[pos..pos + footer_len];
         let mut crefs = Vec::new();
         while footer.len() > 0 {
             let len = footer[0] as usize;
-            assert!(footer.len() > len);
+            if footer.len() <= len {
+                return Err(invalid());
+            }
 
             crefs.push(try!(ChunkRef::from_bytes(&mut &footer[1..1 + len])));
             footer = &footer[len + 1..];
@@ -29,6 +29,9 @@ use util;
 use super::schema;
 
 
+/// Length of the random names given to blobs. Named objects like the root use shorter names.
+pub const BLOB_NAME_LEN: usize = 24;
+
 #[derive(Clone, Debug, Default)]
 pub struct BlobDesc {
     pub name: Vec<u8>,
@@ -63,7 +66,7 @@ impl InternalBlobIndex {
 
     fn new_blob_desc(&mut self) -> BlobDesc {
         BlobDesc {
-            name: randombytes(24),
+            name: randombytes(BLOB_NAME_LEN),
             id: self.next_id(),
         }
     }
@@ -183,6 +186,28 @@ impl InternalBlobIndex {
             .expect("Error deleting blobs");
     }
 
+    fn delete(&mut self, name_: &[u8]) {
+        use super::schema::blobs::dsl::*;
+        diesel::delete(blobs.filter(name.eq(name_)))
+            .execute(&self.conn)
+            .expect("Error deleting blob");
+    }
+
+    fn list_all(&mut self) -> Vec<(BlobDesc, Option<tags::Tag>)> {
+        use super::schema::blobs::dsl::*;
+        blobs.load::<schema::Blob>(&self.conn)
+            .expect("Error listing blobs")
+            .into_iter()
+            .map(|blob_| {
+                (BlobDesc {
+                    id: blob_.id,
+                    name: blob_.name,
+                },
+                 tags::tag_from_num(blob_.tag as i64))
+            })
+            .collect()
+    }
+
     fn list_by_tag(&mut self, tag_: tags::Tag) -> Vec<BlobDesc> {
         use super::schema::blobs::dsl::*;
         blobs.filter(tag.eq(tag_ as i32))
@@ -253,6 +278,16 @@ impl BlobIndex {
         self.lock().delete_by_tag(tag)
     }
 
+    /// Forget a single blob by its external name.
+    pub fn delete(&self, name: &[u8]) {
+        self.lock().delete(name)
+    }
+
+    /// List all known blobs along with their current tag.
+    pub fn list_all(&self) -> Vec<(BlobDesc, Option<tags::Tag>)> {
+        self.lock().list_all()
+    }
+
     pub fn flush(&self) {
         self.lock().new_transaction()
     }
@@ -14,11 +14,13 @@
 
 //! Combines data chunks into larger blobs to be stored externally.
 
+use std::collections::HashSet;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 
 use backend::StoreBackend;
+use crypto;
 use tags;
 use util::FnBox;
 
@@ -29,29 +31,60 @@ mod schema;
 #[cfg(test)]
 pub mod tests;
 
-pub use self::blob::{Blob, ChunkRef, Kind};
-pub use self::index::{BlobDesc, BlobIndex};
+pub use self::blob::{Blob, ChunkRef, Codec, Kind};
+pub use self::index::{BLOB_NAME_LEN, BlobDesc, BlobIndex};
 
 
+/// Differences between the blob index and the blobs found in the backend.
+#[derive(Clone, Debug, Default)]
+pub struct Reconciliation {
+    /// Blobs in the backend that are neither committed nor referenced.
+    pub orphans: Vec<Vec<u8>>,
+    /// Committed or referenced blobs that are not in the backend.
+    pub missing: Vec<Vec<u8>>,
+    /// Objects in the backend with the name of a blob, that are neither known to the blob index
+    /// nor readable as a blob of this repository. They are never deleted.
+    pub unknown: Vec<Vec<u8>>,
+}
+
+/// Whether `name` can be the name of a blob. Named objects (keys, settings, roots) share the
+/// backend, but are never this long; objects of other programs can be, though.
+fn is_blob_name(name: &[u8]) -> bool {
+    name.len() == BLOB_NAME_LEN
+}
+
 pub struct BlobStore<B>(Arc<Mutex<StoreInner<B>>>);
 
 pub struct StoreInner<B> {
     backend: Arc<B>,
+    keeper: Arc<crypto::Keeper>,
+    codec: Codec,
 
     blob_index: Arc<BlobIndex>,
     blob_desc: BlobDesc,
     blob_refs: Vec<(ChunkRef, Box<FnBox<ChunkRef, ()>>)>,
     blob: Blob,
+
+    // A full blob that could not be stored, with the callbacks of its chunks. Its chunk
+    // references have been handed out already, so it is stored again by the next flush.
+    unstored: Option<(BlobDesc, Vec<u8>, Vec<(ChunkRef, Box<FnBox<ChunkRef, ()>>)>)>,
 }
 
 impl<B: StoreBackend> StoreInner<B> {
-    fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> StoreInner<B> {
+    fn new(index: Arc<BlobIndex>,
+           backend: Arc<B>,
+           keeper: Arc<crypto::Keeper>,
+           max_blob_size: usize)
+           -> StoreInner<B> {
         let mut bs = StoreInner {
             backend: backend,
+            keeper: keeper,
+            codec: Codec::Zstd,
             blob_index: index,
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
             blob: Blob::new(max_blob_size),
+            unstored: None,
         };
         bs.reserve_new_blob();
         bs
@@ -61,10 +94,15 @@ impl<B: StoreBackend> StoreInner<B> {
         mem::replace(&mut self.blob_desc, self.blob_index.reserve())
     }
 
-    fn flush(&mut self) {
+    fn flush(&mut self) -> Result<(), String> {
+        // A blob that failed before goes first; until it is stored, the current one waits.
+        if let Some((desc, data, refs)) = self.unstored.take() {
+            try!(self.store_blob(desc, data, refs));
+        }
+
         let length = self.blob.upperbound_len();
         if length == 0 {
-            return;
+            return Ok(());
         }
 
         // Replace blob id
@@ -73,42 +111,74 @@ impl<B: StoreBackend> StoreInner<B> {
         let mut data = Vec::with_capacity(length);
         self.blob.into_bytes(&mut data);
 
+        let refs = mem::replace(&mut self.blob_refs, Vec::new());
         self.blob_index.in_air(&old_blob_desc);
-        self.backend.store(&old_blob_desc.name[..], &data[..]).expect("Store operation failed");
-        self.blob_index.commit_done(&old_blob_desc);
+        self.store_blob(old_blob_desc, data, refs)
+    }
+
+    fn store_blob(&mut self,
+                  desc: BlobDesc,
+                  data: Vec<u8>,
+                  refs: Vec<(ChunkRef, Box<FnBox<ChunkRef, ()>>)>)
+                  -> Result<(), String> {
+        // The blob must be durable before it is recorded as such.
+        let stored = self.backend
+            .store(&desc.name[..], &data[..])
+            .and_then(|()| self.backend.flush());
+        if let Err(e) = stored {
+            // Keep everything for a retry. Until then, the hashes of its chunks stay reserved,
+            // and nothing that refers to them can be committed.
+            self.unstored = Some((desc, data, refs));
+            return Err(e);
+        }
+        self.blob_index.commit_done(&desc);
 
         // Go through callbacks
-        while let Some((blobid, callback)) = self.blob_refs.pop() {
+        for (blobid, callback) in refs.into_iter().rev() {
             callback.call(blobid);
         }
+        Ok(())
     }
 
     fn store(&mut self,
              chunk: Vec<u8>,
              kind: Kind,
              callback: Box<FnBox<ChunkRef, ()>>)
-             -> ChunkRef {
+             -> Result<ChunkRef, String> {
         if chunk.is_empty() {
             let id = ChunkRef {
                 blob_id: vec![0],
                 offset: 0,
                 length: 0,
                 kind: kind,
+                codec: Codec::None,
             };
             let cb_id = id.clone();
             thread::spawn(move || callback.call(cb_id));
-            return id;
+            return Ok(id);
         }
 
+        // Compression has to happen before sealing, as sealed data looks random.
+        let (codec, chunk) = match self.codec.encode(&chunk[..]) {
+            Some(compressed) => (self.codec, compressed),
+            None => (Codec::None, chunk),
+        };
+
+        // Chunks are sealed one by one, so a single chunk can be read back without the rest of
+        // its blob. Offset and length refer to the sealed bytes.
+        let chunk = self.keeper.seal(&chunk[..]);
+
         let mut id = ChunkRef {
             blob_id: self.blob_desc.name.clone(),
             offset: self.blob.chunk_len(),
             length: chunk.len(),
             kind: kind,
+            codec: codec,
         };
 
         if let Err(chunk) = self.blob.try_append(chunk, &id) {
-            self.flush();
+            // On failure, the chunk is not taken; the caller has to forget its hash.
+            try!(self.flush());
 
             id.blob_id = self.blob_desc.name.clone();
             id.offset = 0;
@@ -117,27 +187,67 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_refs.push((id.clone(), callback));
 
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        id
+        Ok(id)
     }
 
     fn retrieve(&mut self, id: &ChunkRef) -> Result<Option<Vec<u8>>, String> {
         if id.offset == 0 && id.length == 0 {
             return Ok(Some(Vec::new()));
         }
-        match self.backend.retrieve(&id.blob_id[..]) {
-            Ok(Some(blob)) => Ok(Some(blob[id.offset..id.offset + id.length].to_vec())),
+        match self.backend.retrieve_range(&id.blob_id[..], id.offset, id.length) {
+            Ok(Some(sealed)) => {
+                let chunk = try!(self.keeper.open(&sealed[..]));
+                Ok(Some(try!(id.codec.decode(chunk))))
+            }
             Ok(None) => Ok(None),
             Err(e) => Err(e),
         }
     }
 
     fn store_named(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
-        try!(self.backend.store(name.as_bytes(), data));
-        Ok(())
+        try!(self.backend.store(name.as_bytes(), &self.keeper.seal(data)[..]));
+        self.backend.flush()
     }
 
     fn retrieve_named(&mut self, name: &str) -> Result<Option<Vec<u8>>, String> {
-        self.backend.retrieve(name.as_bytes())
+        match try!(self.backend.retrieve(name.as_bytes())) {
+            Some(sealed) => Ok(Some(try!(self.keeper.open(&sealed[..])))),
+            None => Ok(None),
+        }
+    }
+
+    fn list_stored(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        Ok(try!(self.backend.list())
+            .into_iter()
+            .filter(|n| is_blob_name(n))
+            .collect())
+    }
+
+    fn read_blob(&mut self, name: &[u8]) -> Result<Option<Vec<(ChunkRef, Vec<u8>)>>, String> {
+        let data = match try!(self.backend.retrieve(name)) {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let refs = try!(Blob::chunk_refs_from_bytes(&data[..]).map_err(|e| e.to_string()));
+
+        let mut chunks = Vec::with_capacity(refs.len());
+        for cref in refs.into_iter() {
+            if cref.offset + cref.length > data.len() {
+                return Err(format!("Chunk at {} is outside of the blob", cref.offset));
+            }
+            let chunk = try!(self.keeper.open(&data[cref.offset..cref.offset + cref.length]));
+            let chunk = try!(cref.codec.decode(chunk));
+            chunks.push((cref, chunk));
+        }
+        Ok(Some(chunks))
+    }
+
+    /// Whether the object `name` reads as a blob with at least one chunk.
+    fn is_readable_blob(&mut self, name: &[u8]) -> bool {
+        match self.read_blob(name) {
+            Ok(Some(chunks)) => !chunks.is_empty(),
+            _ => false,
+        }
     }
 
     fn recover(&mut self, chunk: ChunkRef) {
@@ -168,11 +278,74 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.delete_by_tag(tag);
         Ok(())
     }
+
+    fn reconcile(&mut self,
+                 live: &HashSet<Vec<u8>>,
+                 delete: bool)
+                 -> Result<Reconciliation, String> {
+        let stored: HashSet<Vec<u8>> = try!(self.backend.list())
+            .into_iter()
+            .filter(|n| is_blob_name(n))
+            .collect();
+
+        // Blobs still in progress were either never committed, or are being garbage collected;
+        // in both cases only a reference from a hash keeps them alive.
+        let mut known = HashSet::new();
+        let mut in_progress = Vec::new();
+        for (blob, tag) in self.blob_index.list_all() {
+            if tag == Some(tags::Tag::InProgress) {
+                in_progress.push(blob.name);
+            } else {
+                known.insert(blob.name);
+            }
+        }
+
+        let mut result = Reconciliation::default();
+        for name in stored.iter() {
+            if known.contains(name) || live.contains(name) {
+                continue;
+            }
+            // Only what is recognizably ours may be deleted: blobs the index reserved, or that
+            // read as blobs sealed with our keys. Anything else merely has a similar name.
+            if in_progress.contains(name) || self.is_readable_blob(name) {
+                result.orphans.push(name.clone());
+            } else {
+                result.unknown.push(name.clone());
+            }
+        }
+        for name in known.iter().chain(live.iter().filter(|n| is_blob_name(n))) {
+            if !stored.contains(name) && !result.missing.contains(name) {
+                result.missing.push(name.clone());
+            }
+        }
+        result.orphans.sort();
+        result.missing.sort();
+        result.unknown.sort();
+
+        if delete {
+            for name in result.orphans.iter() {
+                try!(self.backend.delete(&name[..]));
+                self.blob_index.delete(&name[..]);
+            }
+            try!(self.backend.flush());
+            // Also forget unreferenced blobs that never made it to the backend.
+            for name in in_progress.iter().filter(|n| !stored.contains(*n) && !live.contains(*n)) {
+                self.blob_index.delete(&name[..]);
+            }
+            self.blob_index.flush();
+        }
+
+        Ok(result)
+    }
 }
 
 impl<B: StoreBackend> BlobStore<B> {
-    pub fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> BlobStore<B> {
-        BlobStore(Arc::new(Mutex::new(StoreInner::new(index, backend, max_blob_size))))
+    pub fn new(index: Arc<BlobIndex>,
+               backend: Arc<B>,
+               keeper: Arc<crypto::Keeper>,
+               max_blob_size: usize)
+               -> BlobStore<B> {
+        BlobStore(Arc::new(Mutex::new(StoreInner::new(index, backend, keeper, max_blob_size))))
     }
 
     fn lock(&self) -> MutexGuard<StoreInner<B>> {
@@ -186,17 +359,28 @@ impl<B: StoreBackend> BlobStore<B> {
                  chunk: Vec<u8>,
                  kind: Kind,
                  callback: Box<FnBox<ChunkRef, ()>>)
-                 -> ChunkRef {
+                 -> Result<ChunkRef, String> {
         let mut guard = self.lock();
         guard.store(chunk, kind, callback)
     }
 
+    /// Set the compression used for chunks stored from now on. Existing chunks keep the codec
+    /// recorded in their `ChunkRef`.
+    pub fn set_codec(&self, codec: Codec) {
+        self.lock().codec = codec;
+    }
+
+    /// The keys used to seal and hash data in this store.
+    pub fn keeper(&self) -> Arc<crypto::Keeper> {
+        self.lock().keeper.clone()
+    }
+
     /// Retrieve the data chunk identified by `ChunkRef`.
     pub fn retrieve(&self, id: &ChunkRef) -> Result<Option<Vec<u8>>, String> {
         self.lock().retrieve(id)
     }
 
-    /// Store a full named blob (used for writing root).
+    /// Store a full named blob (used for writing root). The blob is sealed as a whole.
     pub fn store_named(&self, name: &str, data: &[u8]) -> Result<(), String> {
         self.lock().store_named(name, data)
     }
@@ -206,6 +390,21 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().retrieve_named(name)
     }
 
+    /// Names of all blobs in the backend.
+    pub fn list_stored(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.lock().list_stored()
+    }
+
+    /// Read all chunks of a blob in the backend, using the chunk references in its footer.
+    pub fn read_blob(&self, name: &[u8]) -> Result<Option<Vec<(ChunkRef, Vec<u8>)>>, String> {
+        self.lock().read_blob(name)
+    }
+
+    /// Names of all blobs in the blob index.
+    pub fn known_blobs(&self) -> HashSet<Vec<u8>> {
+        self.lock().blob_index.list_all().into_iter().map(|(blob, _)| blob.name).collect()
+    }
+
     /// Reinstall a blob recovered from external storage.
     pub fn recover(&self, chunk: ChunkRef) {
         self.lock().recover(chunk)
@@ -223,10 +422,24 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().delete_by_tag(tag)
     }
 
+    /// Compare the blobs in the backend with the blob index. Blobs named in `live` are in use
+    /// even if the index does not know them. With `delete`, orphans are removed from both the
+    /// backend and the index.
+    ///
+    /// Blobs are stored and garbage collected without listing the backend, so no other
+    /// operation may run on the same repository at the same time.
+    pub fn reconcile(&self,
+                     live: &HashSet<Vec<u8>>,
+                     delete: bool)
+                     -> Result<Reconciliation, String> {
+        self.lock().reconcile(live, delete)
+    }
+
     /// Flush the current blob, independent of its size.
-    pub fn flush(&self) {
+    pub fn flush(&self) -> Result<(), String> {
         let mut guard = self.lock();
-        guard.flush();
+        try!(guard.flush());
         guard.blob_index.flush();
+        Ok(())
     }
 }
@@ -15,8 +15,13 @@
 use blob::*;
 
 use backend::{MemoryBackend, StoreBackend};
+use backend::Op;
+use backend::tests::failing;
+use crypto::Keeper;
+use tags;
 
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, mpsc};
 use quickcheck;
 
 #[test]
@@ -25,14 +30,16 @@ fn identity() {
         let backend = Arc::new(MemoryBackend::new());
 
         let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
-        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+        let keeper = Arc::new(Keeper::new_for_testing());
+        let bs_p = BlobStore::new(blob_index, backend.clone(), keeper, 1024);
 
         let mut ids = Vec::new();
         for chunk in chunks.iter() {
-            ids.push((bs_p.store(chunk.to_owned(), Kind::TreeLeaf, Box::new(move |_| {})), chunk));
+            let id = bs_p.store(chunk.to_owned(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+            ids.push((id, chunk));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -60,12 +67,14 @@ fn identity_with_excessive_flushing() {
         let backend = Arc::new(MemoryBackend::new());
 
         let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
-        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+        let keeper = Arc::new(Keeper::new_for_testing());
+        let bs_p = BlobStore::new(blob_index, backend.clone(), keeper, 1024);
 
         let mut ids = Vec::new();
         for chunk in chunks.iter() {
-            ids.push((bs_p.store(chunk.to_owned(), Kind::TreeLeaf, Box::new(move |_| {})), chunk));
-            bs_p.flush();
+            let id = bs_p.store(chunk.to_owned(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+            ids.push((id, chunk));
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
             assert_eq!(bs_p.retrieve(id).unwrap().unwrap(), &chunk[..]);
         }
@@ -92,17 +101,22 @@ fn identity_with_excessive_flushing() {
 
 #[test]
 fn blobid_identity() {
-    fn prop(name: Vec<u8>, offset: usize, length: usize) -> bool {
+    fn prop(name: Vec<u8>, offset: usize, length: usize, compressed: bool) -> bool {
         let blob_id = ChunkRef {
             blob_id: name.to_vec(),
             offset: offset,
             length: length,
             kind: Kind::TreeBranch,
+            codec: if compressed {
+                Codec::Zstd
+            } else {
+                Codec::None
+            },
         };
         let blob_id_bytes = blob_id.as_bytes();
         ChunkRef::from_bytes(&mut &blob_id_bytes[..]).unwrap() == blob_id
     }
-    quickcheck::quickcheck(prop as fn(Vec<u8>, usize, usize) -> bool);
+    quickcheck::quickcheck(prop as fn(Vec<u8>, usize, usize, bool) -> bool);
 }
 
 #[test]
@@ -112,6 +126,7 @@ fn blob_reuse() {
         offset: 0,
         length: 1,
         kind: Kind::TreeLeaf,
+        codec: Codec::None,
     };
     let mut b = Blob::new(100);
     b.try_append(vec![1, 2, 3], &c).unwrap();
@@ -143,12 +158,14 @@ fn blob_identity() {
                                              offset: 0,
                                              length: chunk.len(),
                                              kind: Kind::TreeLeaf,
+                                             codec: Codec::None,
                                          }) {
                 assert!(b.upperbound_len() + chunk.len() + 50 >= max_size);
                 break;
             }
         }
 
+        let upperbound = b.upperbound_len();
         let mut out = Vec::new();
         b.into_bytes(&mut out);
 
@@ -157,7 +174,8 @@ fn blob_identity() {
             return true;
         }
 
-        assert_eq!(max_size, out.len());
+        // Blobs are not padded.
+        assert!(out.len() <= upperbound);
 
         let crefs = Blob::chunk_refs_from_bytes(&out).unwrap();
         assert_eq!(chunks.len(), crefs.len());
@@ -174,3 +192,179 @@ fn blob_identity() {
     }
     quickcheck::quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
 }
+
+#[test]
+fn blob_with_legacy_padding() {
+    let c = ChunkRef {
+        blob_id: Vec::new(),
+        offset: 0,
+        length: 3,
+        kind: Kind::TreeLeaf,
+        codec: Codec::None,
+    };
+    let mut b = Blob::new(1000);
+    b.try_append(vec![1, 2, 3], &c).unwrap();
+    b.try_append(vec![4, 5, 6], &c).unwrap();
+
+    let mut out = Vec::new();
+    b.into_bytes(&mut out);
+    assert!(out.len() < 100);
+
+    // Older versions padded blobs with zeros up to their max size, between chunks and footer.
+    let mut padded = out[..6].to_vec();
+    padded.extend_from_slice(&vec![0; 1000 - out.len()][..]);
+    padded.extend_from_slice(&out[6..]);
+    assert_eq!(1000, padded.len());
+
+    assert_eq!(Blob::chunk_refs_from_bytes(&padded[..]).unwrap(), vec![c.clone(), c]);
+}
+
+#[test]
+fn truncated_blob_does_not_panic() {
+    let c = ChunkRef {
+        blob_id: Vec::new(),
+        offset: 0,
+        length: 3,
+        kind: Kind::TreeLeaf,
+        codec: Codec::None,
+    };
+    let mut b = Blob::new(1000);
+    b.try_append(vec![1, 2, 3], &c).unwrap();
+    b.try_append(vec![4, 5, 6], &c).unwrap();
+
+    let mut out = Vec::new();
+    b.into_bytes(&mut out);
+    assert!(Blob::chunk_refs_from_bytes(&out[..]).is_ok());
+
+    // A partially written blob can end anywhere.
+    for len in 0..out.len() {
+        let _ = Blob::chunk_refs_from_bytes(&out[..len]);
+    }
+}
+
+#[test]
+fn backend_never_sees_plaintext() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let keeper = Arc::new(Keeper::new_for_testing());
+    let bs_p = BlobStore::new(blob_index, backend.clone(), keeper, 1024);
+
+    let secret = b"very secret file contents".to_vec();
+    let id = bs_p.store(secret.clone(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    bs_p.flush().unwrap();
+    bs_p.store_named("root", &secret[..]).unwrap();
+
+    for name in vec![id.blob_id.clone(), b"root".to_vec()] {
+        let stored = backend.retrieve(&name[..]).unwrap().unwrap();
+        assert!(!stored.windows(secret.len()).any(|w| w == &secret[..]));
+    }
+
+    assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), secret);
+    assert_eq!(bs_p.retrieve_named("root").unwrap().unwrap(), secret);
+}
+
+#[test]
+fn compression() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let keeper = Arc::new(Keeper::new_for_testing());
+    let bs_p = BlobStore::new(blob_index, backend.clone(), keeper.clone(), 1024);
+
+    // Repetitive data is compressed.
+    let text = vec![b'a'; 800];
+    let text_id = bs_p.store(text.clone(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    assert_eq!(text_id.codec, Codec::Zstd);
+    assert!(text_id.length < 100);
+
+    // Sealed data looks random, so compressing it does not pay off.
+    let noise = keeper.seal(&[0; 500]);
+    let noise_id = bs_p.store(noise.clone(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    assert_eq!(noise_id.codec, Codec::None);
+
+    // Disabling compression only affects new chunks.
+    bs_p.set_codec(Codec::None);
+    let plain_id = bs_p.store(text.clone(), Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    assert_eq!(plain_id.codec, Codec::None);
+
+    bs_p.flush().unwrap();
+    assert_eq!(bs_p.retrieve(&text_id).unwrap().unwrap(), text);
+    assert_eq!(bs_p.retrieve(&noise_id).unwrap().unwrap(), noise);
+    assert_eq!(bs_p.retrieve(&plain_id).unwrap().unwrap(), text);
+}
+
+#[test]
+fn reconcile() {
+    let backend = Arc::new(MemoryBackend::new());
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let keeper = Arc::new(Keeper::new_for_testing());
+    let bs_p = BlobStore::new(blob_index.clone(), backend.clone(), keeper.clone(), 1024);
+
+    let id = bs_p.store(vec![1, 2, 3], Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    bs_p.flush().unwrap();
+    bs_p.store_named("root", b"root").unwrap();
+
+    // Left behind by a crash after storing, before the blob was committed.
+    let in_air = blob_index.reserve();
+    blob_index.in_air(&in_air);
+    backend.store(&in_air.name[..], b"in air").unwrap();
+    // Not known at all, e.g. after the local index was lost.
+    let lost_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let other = BlobStore::new(lost_index, backend.clone(), keeper.clone(), 1024);
+    let forgotten = other.store(vec![4, 5, 6], Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    other.flush().unwrap();
+    // Not a blob at all, but with the name of one.
+    let unknown = vec![1; BLOB_NAME_LEN];
+    backend.store(&unknown[..], b"unknown").unwrap();
+    // Committed, but gone from the backend.
+    let lost = blob_index.recover(vec![2; BLOB_NAME_LEN]);
+
+    let mut live = HashSet::new();
+    live.insert(id.blob_id.clone());
+
+    let mut orphans = vec![in_air.name.clone(), forgotten.blob_id.clone()];
+    orphans.sort();
+    let report = bs_p.reconcile(&live, false).unwrap();
+    assert_eq!(report.orphans, orphans);
+    assert_eq!(report.missing, vec![lost.name.clone()]);
+    assert_eq!(report.unknown, vec![unknown.clone()]);
+    assert!(backend.retrieve(&forgotten.blob_id[..]).unwrap().is_some());
+
+    let deleted = bs_p.reconcile(&live, true).unwrap();
+    assert_eq!(deleted.orphans, orphans);
+    assert!(backend.retrieve(&in_air.name[..]).unwrap().is_none());
+    assert!(backend.retrieve(&forgotten.blob_id[..]).unwrap().is_none());
+    assert!(blob_index.list_by_tag(tags::Tag::InProgress).is_empty());
+
+    // Live and named blobs, and objects that are not blobs, are untouched.
+    let report = bs_p.reconcile(&live, false).unwrap();
+    assert!(report.orphans.is_empty());
+    assert_eq!(report.unknown, vec![unknown.clone()]);
+    assert!(backend.retrieve(&unknown[..]).unwrap().is_some());
+    assert_eq!(report.missing, vec![lost.name]);
+    assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), vec![1, 2, 3]);
+    assert!(backend.retrieve(b"root").unwrap().is_some());
+}
+
+#[test]
+fn store_failure_is_retried() {
+    let backend = Arc::new(failing(Op::Store, 1));
+    let blob_index = Arc::new(BlobIndex::new_for_testing().unwrap());
+    let keeper = Arc::new(Keeper::new_for_testing());
+    let bs_p = BlobStore::new(blob_index.clone(), backend.clone(), keeper, 1024);
+
+    let (sender, receiver) = mpsc::channel();
+    let callback = Box::new(move |id: ChunkRef| sender.send(id).unwrap());
+    let failed = bs_p.store(vec![1, 2, 3], Kind::TreeLeaf, callback).unwrap();
+    assert!(bs_p.flush().is_err());
+    // The failed blob is left in progress, and its chunk is not committed.
+    assert_eq!(blob_index.list_by_tag(tags::Tag::InProgress).len(), 1);
+    assert!(receiver.try_recv().is_err());
+
+    // The next flush stores it again, so the reference handed out for its chunk is valid.
+    let id = bs_p.store(vec![4, 5, 6], Kind::TreeLeaf, Box::new(move |_| {})).unwrap();
+    bs_p.flush().unwrap();
+    assert_eq!(receiver.try_recv().unwrap(), failed);
+    assert_eq!(blob_index.list_by_tag(tags::Tag::InProgress).len(), 0);
+    assert_eq!(bs_p.retrieve(&failed).unwrap().unwrap(), vec![1, 2, 3]);
+    assert_eq!(bs_p.retrieve(&id).unwrap().unwrap(), vec![4, 5, 6]);
+}
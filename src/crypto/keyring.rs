@@ -0,0 +1,276 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passphrase protected storage of the repository secrets.
+//!
+//! The secrets are generated once per repository. Each user unlocks them through their own
+//! `KeySlot`, which holds a copy of the secrets sealed under a key derived from that user's
+//! passphrase. The key ring is stored externally next to "root", so that a repository can be
+//! unlocked after all local state is gone.
+
+use std::cmp;
+
+use capnp;
+use sodiumoxide::crypto::{pwhash, secretbox};
+use sodiumoxide::randombytes::randombytes;
+
+use backend::StoreBackend;
+use crypto::Keeper;
+use errors::HatError;
+use hash;
+use root_capnp;
+
+
+/// Name of the external object holding the key ring.
+pub const KEYRING_NAME: &'static str = "keys";
+
+const KEYRING_VERSION: u32 = 1;
+
+pub const HASH_KEY_BYTES: usize = 32;
+
+
+/// The secret key material of a repository.
+pub struct Secrets {
+    pub blob_key: secretbox::Key,
+
+    /// Key for content hashes. Repositories that were created with unkeyed hashes have none, as
+    /// their existing hashes would no longer match.
+    pub hash_key: Option<Vec<u8>>,
+}
+
+impl Secrets {
+    /// Generate secrets for a new repository.
+    pub fn generate() -> Secrets {
+        Secrets {
+            blob_key: secretbox::gen_key(),
+            hash_key: Some(randombytes(HASH_KEY_BYTES)),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::secrets::Builder>();
+            root.set_blob_key(&self.blob_key.0[..]);
+            if let Some(ref key) = self.hash_key {
+                root.set_hash_key(&key[..]);
+            }
+        }
+
+        let mut out = Vec::new();
+        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Secrets, HatError> {
+        let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                       capnp::message::ReaderOptions::new()));
+        let root = try!(reader.get_root::<root_capnp::secrets::Reader>());
+        let blob_key = try!(secretbox::Key::from_slice(try!(root.get_blob_key()))
+            .ok_or("Invalid blob key in key ring"));
+
+        let hash_key = try!(root.get_hash_key());
+        if !hash_key.is_empty() && !hash::valid_key_len(hash_key.len()) {
+            return Err(From::from("Invalid hash key in key ring"));
+        }
+
+        Ok(Secrets {
+            blob_key: blob_key,
+            hash_key: if hash_key.is_empty() {
+                None
+            } else {
+                Some(hash_key.to_owned())
+            },
+        })
+    }
+}
+
+
+struct KeySlot {
+    id: u64,
+    salt: pwhash::Salt,
+    ops_limit: pwhash::OpsLimit,
+    mem_limit: pwhash::MemLimit,
+    sealed_secrets: Vec<u8>,
+}
+
+fn wrapping_key(passphrase: &[u8],
+                salt: &pwhash::Salt,
+                ops_limit: pwhash::OpsLimit,
+                mem_limit: pwhash::MemLimit)
+                -> Result<Keeper, HatError> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    try!(pwhash::derive_key(&mut key.0[..], passphrase, salt, ops_limit, mem_limit)
+        .map(|_| ())
+        .map_err(|()| "Could not derive key from passphrase"));
+
+    Ok(Keeper::new(key))
+}
+
+impl KeySlot {
+    fn new(id: u64, secrets: &Secrets, passphrase: &[u8]) -> Result<KeySlot, HatError> {
+        let salt = pwhash::gen_salt();
+        let ops_limit = pwhash::OPSLIMIT_INTERACTIVE;
+        let mem_limit = pwhash::MEMLIMIT_INTERACTIVE;
+
+        let wrapper = try!(wrapping_key(passphrase, &salt, ops_limit, mem_limit));
+
+        Ok(KeySlot {
+            id: id,
+            salt: salt,
+            ops_limit: ops_limit,
+            mem_limit: mem_limit,
+            sealed_secrets: wrapper.seal(&secrets.to_bytes()[..]),
+        })
+    }
+
+    fn unlock(&self, passphrase: &[u8]) -> Option<Secrets> {
+        let wrapper = match wrapping_key(passphrase, &self.salt, self.ops_limit, self.mem_limit) {
+            Ok(w) => w,
+            Err(_) => return None,
+        };
+        match wrapper.open(&self.sealed_secrets[..]) {
+            Ok(bytes) => Secrets::from_bytes(&bytes[..]).ok(),
+            Err(_) => None,
+        }
+    }
+}
+
+
+/// The set of key slots that can unlock a repository.
+pub struct KeyRing {
+    slots: Vec<KeySlot>,
+}
+
+impl KeyRing {
+    /// Create a key ring with a single slot protecting `secrets` with `passphrase`.
+    pub fn new(secrets: &Secrets, passphrase: &[u8]) -> Result<KeyRing, HatError> {
+        Ok(KeyRing { slots: vec![try!(KeySlot::new(1, secrets, passphrase))] })
+    }
+
+    /// Read the key ring from external storage. Returns `None` if the repository has none.
+    pub fn load<B: StoreBackend>(backend: &B) -> Result<Option<KeyRing>, HatError> {
+        match try!(backend.retrieve(KEYRING_NAME.as_bytes())) {
+            Some(bytes) => Ok(Some(try!(KeyRing::from_bytes(&bytes[..])))),
+            None => Ok(None),
+        }
+    }
+
+    /// Write the key ring to external storage, replacing any previous version.
+    pub fn store<B: StoreBackend>(&self, backend: &B) -> Result<(), HatError> {
+        try!(backend.store(KEYRING_NAME.as_bytes(), &self.to_bytes()[..]));
+        try!(backend.flush());
+        Ok(())
+    }
+
+    /// List the ids of all key slots.
+    pub fn ids(&self) -> Vec<u64> {
+        self.slots.iter().map(|s| s.id).collect()
+    }
+
+    /// Unlock the repository secrets using any slot that accepts `passphrase`.
+    /// Returns the id of the slot used along with the secrets.
+    pub fn unlock(&self, passphrase: &[u8]) -> Option<(u64, Secrets)> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.unlock(passphrase).map(|secrets| (s.id, secrets)))
+            .next()
+    }
+
+    /// Add a new slot protecting `secrets` with `passphrase`. Returns the id of the new slot.
+    pub fn add(&mut self, secrets: &Secrets, passphrase: &[u8]) -> Result<u64, HatError> {
+        let id = 1 + self.slots.iter().map(|s| s.id).max().unwrap_or(0);
+        self.slots.push(try!(KeySlot::new(id, secrets, passphrase)));
+        Ok(id)
+    }
+
+    /// Remove the slot with the given id. The last slot can not be removed.
+    pub fn remove(&mut self, id: u64) -> Result<(), HatError> {
+        if !self.slots.iter().any(|s| s.id == id) {
+            return Err(From::from(format!("No key with id {}", id)));
+        }
+        if self.slots.len() == 1 {
+            return Err(From::from("Refusing to remove the last key"));
+        }
+        self.slots.retain(|s| s.id != id);
+        Ok(())
+    }
+
+    /// Replace the passphrase of the slot unlocked by `old_passphrase`.
+    /// Returns the id of the updated slot.
+    pub fn change_passphrase(&mut self,
+                             old_passphrase: &[u8],
+                             new_passphrase: &[u8])
+                             -> Result<u64, HatError> {
+        let (id, secrets) = try!(self.unlock(old_passphrase)
+            .ok_or("Could not unlock any key with the given passphrase"));
+        let slot = try!(KeySlot::new(id, &secrets, new_passphrase));
+        for s in self.slots.iter_mut() {
+            if s.id == id {
+                *s = slot;
+                break;
+            }
+        }
+        Ok(id)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::key_ring::Builder>();
+            root.set_version(KEYRING_VERSION);
+            let mut slots = root.init_slots(self.slots.len() as u32);
+            for (i, slot) in self.slots.iter().enumerate() {
+                let mut s = slots.borrow().get(i as u32);
+                s.set_id(slot.id);
+                s.set_salt(&slot.salt.0[..]);
+                s.set_ops_limit(slot.ops_limit.0 as u64);
+                s.set_mem_limit(slot.mem_limit.0 as u64);
+                s.set_sealed_secrets(&slot.sealed_secrets[..]);
+            }
+        }
+
+        let mut out = Vec::new();
+        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<KeyRing, HatError> {
+        let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                       capnp::message::ReaderOptions::new()));
+        let root = try!(reader.get_root::<root_capnp::key_ring::Reader>());
+        if root.get_version() != KEYRING_VERSION {
+            return Err(From::from(format!("Unsupported key ring version: {}",
+                                          root.get_version())));
+        }
+
+        let mut slots = Vec::new();
+        for s in try!(root.get_slots()).iter() {
+            slots.push(KeySlot {
+                id: s.get_id(),
+                salt: try!(pwhash::Salt::from_slice(try!(s.get_salt()))
+                    .ok_or("Invalid salt in key ring")),
+                // Anyone with access to the backend can edit the limits; never derive keys with
+                // less effort than slots are created with.
+                ops_limit: pwhash::OpsLimit(cmp::max(s.get_ops_limit() as usize,
+                                                     pwhash::OPSLIMIT_INTERACTIVE.0)),
+                mem_limit: pwhash::MemLimit(cmp::max(s.get_mem_limit() as usize,
+                                                     pwhash::MEMLIMIT_INTERACTIVE.0)),
+                sealed_secrets: try!(s.get_sealed_secrets()).to_owned(),
+            });
+        }
+
+        Ok(KeyRing { slots: slots })
+    }
+}
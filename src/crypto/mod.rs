@@ -0,0 +1,134 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authenticated encryption of everything we hand to external storage.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use sodiumoxide::crypto::secretbox;
+
+use errors::HatError;
+use hash;
+
+mod keyring;
+#[cfg(test)]
+mod tests;
+
+pub use self::keyring::{KEYRING_NAME, KeyRing, Secrets};
+
+
+/// Holds the secret key material of a repository.
+///
+/// All data chunks and named objects are sealed with XSalsa20-Poly1305 (`secretbox`) before
+/// they reach a `StoreBackend`. Every sealed message carries its own random nonce, so the
+/// output of `seal()` is `nonce || ciphertext`.
+///
+/// Content hashes are keyed with a separate secret, when the repository has one, so that hashes
+/// seen in indexes or blob footers do not reveal whether a known file is in a snapshot.
+///
+/// Repositories created before encryption have no keys at all; their keeper stores data as is.
+pub struct Keeper {
+    blob_key: Option<secretbox::Key>,
+    hash_key: Option<Vec<u8>>,
+}
+
+impl Keeper {
+    /// Create a keeper that seals with `blob_key` and uses unkeyed hashing.
+    pub fn new(blob_key: secretbox::Key) -> Keeper {
+        Keeper {
+            blob_key: Some(blob_key),
+            hash_key: None,
+        }
+    }
+
+    /// Create a keeper for a repository written before encryption. Nothing is sealed, and
+    /// hashing is unkeyed.
+    pub fn plaintext() -> Keeper {
+        Keeper {
+            blob_key: None,
+            hash_key: None,
+        }
+    }
+
+    pub fn from_secrets(secrets: &Secrets) -> Keeper {
+        Keeper {
+            blob_key: Some(secrets.blob_key.clone()),
+            hash_key: secrets.hash_key.clone(),
+        }
+    }
+
+    /// Read the plain key file used by repositories created before the key ring existed.
+    pub fn read_key_file(path: &Path) -> Result<Option<secretbox::Key>, HatError> {
+        let mut fd = match fs::File::open(path) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        let mut bytes = Vec::new();
+        try!(fd.read_to_end(&mut bytes));
+        match secretbox::Key::from_slice(&bytes[..]) {
+            Some(key) => Ok(Some(key)),
+            None => Err(From::from(format!("Invalid key file: {}", path.display()))),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn new_for_testing() -> Keeper {
+        // Fixed keys let tests reopen data written by an earlier instance.
+        Keeper {
+            blob_key: Some(secretbox::Key([7; secretbox::KEYBYTES])),
+            hash_key: Some(vec![9; keyring::HASH_KEY_BYTES]),
+        }
+    }
+
+    /// Compute the content hash of `data`.
+    pub fn hash(&self, data: &[u8]) -> hash::Hash {
+        match self.hash_key {
+            Some(ref key) => hash::Hash::new_keyed(&key[..], data),
+            None => hash::Hash::new(data),
+        }
+    }
+
+    /// Encrypt and authenticate `plain` under a fresh random nonce. Without a key, `plain` is
+    /// returned as is.
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let key = match self.blob_key {
+            Some(ref key) => key,
+            None => return plain.to_vec(),
+        };
+        let nonce = secretbox::gen_nonce();
+        let mut out = Vec::with_capacity(secretbox::NONCEBYTES + secretbox::MACBYTES +
+                                         plain.len());
+        out.extend_from_slice(&nonce.0[..]);
+        out.extend_from_slice(&secretbox::seal(plain, &nonce, key)[..]);
+        out
+    }
+
+    /// Verify and decrypt data produced by `seal()`. Without a key, `sealed` is returned as is.
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, String> {
+        let key = match self.blob_key {
+            Some(ref key) => key,
+            None => return Ok(sealed.to_vec()),
+        };
+        if sealed.len() < secretbox::NONCEBYTES + secretbox::MACBYTES {
+            return Err("Sealed data is too short".to_owned());
+        }
+        let nonce = secretbox::Nonce::from_slice(&sealed[..secretbox::NONCEBYTES])
+            .expect("NONCEBYTES");
+        secretbox::open(&sealed[secretbox::NONCEBYTES..], &nonce, key)
+            .map_err(|()| "Could not authenticate sealed data".to_owned())
+    }
+}
+
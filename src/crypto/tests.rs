@@ -0,0 +1,119 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use crypto::*;
+use hash;
+use sodiumoxide::crypto::secretbox;
+use backend::MemoryBackend;
+use quickcheck;
+
+#[test]
+fn seal_open_identity() {
+    fn prop(data: Vec<u8>) -> bool {
+        let keeper = Keeper::new_for_testing();
+        keeper.open(&keeper.seal(&data[..])[..]).unwrap() == data
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool);
+}
+
+#[test]
+fn open_rejects_tampering() {
+    let keeper = Keeper::new_for_testing();
+    let mut sealed = keeper.seal(b"some secret");
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert!(keeper.open(&sealed[..]).is_err());
+    assert!(keeper.open(&sealed[..10]).is_err());
+}
+
+#[test]
+fn keyring_unlock() {
+    let secrets = Secrets::generate();
+    let mut ring = KeyRing::new(&secrets, b"alice").unwrap();
+    let bob = ring.add(&secrets, b"bob").unwrap();
+    assert_eq!(ring.ids(), vec![1, bob]);
+
+    let (id, unlocked) = ring.unlock(b"bob").unwrap();
+    assert_eq!(id, bob);
+    assert_eq!(unlocked.blob_key.0, secrets.blob_key.0);
+    assert_eq!(unlocked.hash_key, secrets.hash_key);
+    assert!(ring.unlock(b"mallory").is_none());
+
+    // Both keys unlock the same data.
+    let sealed = Keeper::from_secrets(&ring.unlock(b"alice").unwrap().1).seal(b"data");
+    assert_eq!(Keeper::from_secrets(&unlocked).open(&sealed[..]).unwrap(), b"data".to_vec());
+}
+
+#[test]
+fn keyring_remove_and_change_passphrase() {
+    let secrets = Secrets::generate();
+    let mut ring = KeyRing::new(&secrets, b"alice").unwrap();
+    assert!(ring.remove(1).is_err());  // Last key.
+
+    let bob = ring.add(&secrets, b"bob").unwrap();
+    assert!(ring.remove(bob + 1).is_err());  // Unknown key.
+    ring.remove(1).unwrap();
+    assert!(ring.unlock(b"alice").is_none());
+
+    assert!(ring.change_passphrase(b"alice", b"carol").is_err());
+    assert_eq!(ring.change_passphrase(b"bob", b"carol").unwrap(), bob);
+    assert!(ring.unlock(b"bob").is_none());
+    assert_eq!(ring.unlock(b"carol").unwrap().0, bob);
+}
+
+#[test]
+fn keyring_rejects_invalid_hash_key() {
+    for len in vec![8, 65] {
+        let secrets = Secrets {
+            blob_key: secretbox::gen_key(),
+            hash_key: Some(vec![1; len]),
+        };
+        let ring = KeyRing::new(&secrets, b"alice").unwrap();
+        assert!(ring.unlock(b"alice").is_none());
+    }
+}
+
+#[test]
+fn keyring_load_store() {
+    let backend = MemoryBackend::new();
+    assert!(KeyRing::load(&backend).unwrap().is_none());
+
+    let secrets = Secrets::generate();
+    KeyRing::new(&secrets, b"alice").unwrap().store(&backend).unwrap();
+
+    let ring = KeyRing::load(&backend).unwrap().unwrap();
+    assert_eq!(ring.unlock(b"alice").unwrap().1.blob_key.0, secrets.blob_key.0);
+}
+
+#[test]
+fn keyed_hash() {
+    let keyed = Keeper::new_for_testing();
+    let unkeyed = Keeper::new(secretbox::Key([7; secretbox::KEYBYTES]));
+
+    assert_eq!(unkeyed.hash(b"data"), hash::Hash::new(b"data"));
+    assert!(keyed.hash(b"data") != hash::Hash::new(b"data"));
+    assert_eq!(keyed.hash(b"data"), Keeper::new_for_testing().hash(b"data"));
+
+    let other = Keeper::from_secrets(&Secrets::generate());
+    assert!(other.hash(b"data") != keyed.hash(b"data"));
+}
+
+#[test]
+fn unkeyed_secrets_stay_unkeyed() {
+    let mut secrets = Secrets::generate();
+    secrets.hash_key = None;
+    let ring = KeyRing::new(&secrets, b"alice").unwrap();
+    assert_eq!(ring.unlock(b"alice").unwrap().1.hash_key, None);
+}
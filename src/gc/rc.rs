@@ -29,6 +29,24 @@ pub struct GcRc<B> {
     backend: B,
 }
 
+impl<B: gc::GcBackend> GcRc<B> {
+    /// The number of snapshot references to `id`.
+    pub fn refcount(&self, id: gc::Id) -> Result<i64, B::Err> {
+        Ok(try!(self.backend.get_data(id, DATA_FAMILY)).num)
+    }
+
+    /// Overwrite the number of snapshot references to `id`, e.g. after recounting them.
+    pub fn set_refcount(&mut self, id: gc::Id, count: i64) -> Result<(), B::Err> {
+        try!(self.backend.update_data(id, DATA_FAMILY, move |GcData { bytes, .. }| {
+            Some(GcData {
+                num: count,
+                bytes: bytes,
+            })
+        }));
+        Ok(())
+    }
+}
+
 impl<B: gc::GcBackend> gc::Gc<B> for GcRc<B> {
     type Err = B::Err;
 
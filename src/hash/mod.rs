@@ -56,19 +56,37 @@ pub struct GcData {
 pub trait UpdateFn: FnOnce(GcData) -> Option<GcData> {}
 impl<T> UpdateFn for T where T: FnOnce(GcData) -> Option<GcData> {}
 
+/// Bounds on the length of a hash key, as accepted by BLAKE2b.
+pub const KEY_BYTES_MIN: usize = 16;
+pub const KEY_BYTES_MAX: usize = 64;
+
+/// Whether a hash key of `len` bytes can be used.
+pub fn valid_key_len(len: usize) -> bool {
+    KEY_BYTES_MIN <= len && len <= KEY_BYTES_MAX
+}
+
 impl Hash {
     /// Computes `hash(text)` and stores this digest as the `bytes` field in a new `Hash` structure.
     pub fn new(text: &[u8]) -> Hash {
+        Hash::new_keyed(&[], text)
+    }
+
+    /// Computes the keyed hash of `text`. Without knowing `key`, the digest can not be used to
+    /// confirm a guess about `text`. An empty key gives the same result as `Hash::new`; other
+    /// keys must be between `KEY_BYTES_MIN` and `KEY_BYTES_MAX` bytes long.
+    pub fn new_keyed(key: &[u8], text: &[u8]) -> Hash {
+        assert!(key.is_empty() || valid_key_len(key.len()));
         let digest_len = libsodium_sys::crypto_generichash_blake2b_BYTES_MAX;
         let mut digest = vec![0; digest_len];
-        unsafe {
+        let ret = unsafe {
             libsodium_sys::crypto_generichash_blake2b(digest.as_mut_ptr(),
                                                       digest_len,
                                                       text.as_ptr(),
                                                       text.len() as u64,
-                                                      vec![].as_ptr(),
-                                                      0);
-        }
+                                                      key.as_ptr(),
+                                                      key.len())
+        };
+        assert_eq!(ret, 0);
         Hash { bytes: digest }
     }
 }
@@ -260,6 +278,17 @@ impl InternalHashIndex {
         }
     }
 
+    fn unreserve(&mut self, hash: &Hash) {
+        let unused = self.queue
+            .find_value_of_key(&hash.bytes)
+            .map_or(false, |qe| qe.persistent_ref.is_none());
+        if unused {
+            self.queue.remove(&hash.bytes);
+            // Entries after it may be complete now.
+            self.insert_completed_in_order();
+        }
+    }
+
     fn insert_completed_in_order(&mut self) {
         use self::schema::hashes::dsl::*;
 
@@ -563,6 +592,13 @@ impl HashIndex {
         self.lock().update_reserved(hash_entry);
     }
 
+    /// Forget a reserved `Hash` whose content could not be stored. Hashes that already have a
+    /// persistent reference, which may have been handed out, are kept.
+    pub fn unreserve(&self, hash: &Hash) {
+        assert!(!hash.bytes.is_empty());
+        self.lock().unreserve(hash);
+    }
+
     /// A `Hash` is committed when it has been `finalized` in the external storage. `Commit`
     /// includes the persistent reference that the content is available at.
     pub fn commit(&self, hash: &Hash, persistent_ref: blob::ChunkRef) {
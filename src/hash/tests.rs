@@ -16,7 +16,7 @@ use hash::tree::*;
 
 use std::sync::{Arc, Mutex};
 
-use blob::{ChunkRef, Kind};
+use blob::{ChunkRef, Codec, Kind};
 use hash::Hash;
 use key;
 
@@ -46,6 +46,10 @@ impl MemoryBackend {
 impl HashTreeBackend for MemoryBackend {
     type Err = key::MsgError;
 
+    fn hash(&self, data: &[u8]) -> Hash {
+        Hash::new(data)
+    }
+
     fn fetch_chunk(&self,
                    hash: &Hash,
                    ref_opt: Option<ChunkRef>)
@@ -76,6 +80,7 @@ impl HashTreeBackend for MemoryBackend {
                     } else {
                         Kind::TreeBranch
                     },
+                    codec: Codec::None,
                 })
             }
             None => None,
@@ -105,6 +110,7 @@ impl HashTreeBackend for MemoryBackend {
             } else {
                 Kind::TreeBranch
             },
+            codec: Codec::None,
         })
     }
 }
@@ -25,6 +25,8 @@ use root_capnp;
 use blob::{ChunkRef, Kind};
 use hash::{Entry, HASHBYTES, Hash};
 
+#[cfg(test)]
+use blob::Codec;
 #[cfg(test)]
 use quickcheck;
 
@@ -61,6 +63,9 @@ pub trait HashTreeBackend: Clone {
     fn fetch_payload(&self, &Hash) -> Option<Vec<u8>>;
     fn fetch_persistent_ref(&self, &Hash) -> Option<ChunkRef>;
     fn insert_chunk(&self, &Hash, i64, Option<Vec<u8>>, Vec<u8>) -> Result<ChunkRef, Self::Err>;
+
+    /// Compute the hash identifying a chunk of data.
+    fn hash(&self, &[u8]) -> Hash;
 }
 
 
@@ -96,6 +101,20 @@ fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
     Some(out)
 }
 
+/// Decode the children of a tree branch: their hashes and persistent references.
+pub fn decode_branch(bytes: &[u8]) -> Result<Vec<(Hash, ChunkRef)>, capnp::Error> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                            capnp::message::ReaderOptions::new()));
+    let msg = try!(reader.get_root::<root_capnp::hash_ref_list::Reader>());
+
+    let mut out = Vec::new();
+    for ref_ in try!(msg.get_hash_refs()).iter() {
+        let hash_ref = try!(HashRef::read_msg(&ref_));
+        out.push((Hash { bytes: hash_ref.hash }, hash_ref.persistent_ref));
+    }
+    Ok(out)
+}
+
 #[test]
 fn test_hash_refs_identity() {
     fn prop(count: u8, hash: Vec<u8>, blob: Vec<u8>, n: usize) -> bool {
@@ -104,6 +123,7 @@ fn test_hash_refs_identity() {
             offset: n,
             length: n,
             kind: Kind::TreeBranch,
+            codec: Codec::None,
         };
         let mut v = vec![];
         for _ in 0..count {
@@ -180,7 +200,7 @@ impl<B: HashTreeBackend> SimpleHashTreeWriter<B> {
                  data: Vec<u8>,
                  metadata: Option<Vec<u8>>)
                  -> Result<(), B::Err> {
-        let hash = Hash::new(&data[..]);
+        let hash = self.backend.hash(&data[..]);
         let persistent_ref = try!(self.backend.insert_chunk(&hash, level as i64, metadata, data));
         let hash_ref = HashRef {
             hash: hash.bytes,
@@ -418,14 +438,11 @@ impl<B: HashTreeBackend> SimpleHashTreeReader<B> {
 }
 
 
-impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
-    type Item = Vec<u8>;
-
-    /// Read the next block of the hash-tree.
-    /// This operation can be expensive, as it may require fetching a file through the backend.
-    fn next(&mut self) -> Option<Vec<u8>> {
+impl<B: HashTreeBackend> ReaderResult<B> {
+    /// Read the next block of the hash-tree, reporting backend errors instead of panicking.
+    pub fn try_next(&mut self) -> Result<Option<Vec<u8>>, B::Err> {
         let (force_empty, res) = match *self {
-            ReaderResult::Tree(ref mut it) => (false, it.extract().unwrap()),
+            ReaderResult::Tree(ref mut it) => (false, try!(it.extract())),
             ReaderResult::SingleBlock(ref b) => (true, Some(b.clone())),
             ReaderResult::Empty => (true, None),
         };
@@ -433,6 +450,16 @@ impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
             *self = ReaderResult::Empty;
         }
 
-        res
+        Ok(res)
+    }
+}
+
+impl<B: HashTreeBackend> Iterator for ReaderResult<B> {
+    type Item = Vec<u8>;
+
+    /// Read the next block of the hash-tree.
+    /// This operation can be expensive, as it may require fetching a file through the backend.
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.try_next().unwrap()
     }
 }
@@ -0,0 +1,287 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verify that the snapshots in a repository can be read back.
+
+use rustc_serialize::hex::ToHex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use gc;
+use hash;
+
+use super::{Family, HatRc};
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The hash is not in the hash index.
+    MissingHash,
+    /// The hash is in the hash index, but was never stored.
+    Uncommitted,
+    /// A tree node of the hash index does not list its children.
+    MissingChildren,
+    /// The chunk is in a blob that the blob index does not know.
+    UnknownBlob(Vec<u8>),
+    /// The chunk could not be read from the backend.
+    Unreadable(String),
+    /// The backend does not have the chunk.
+    MissingChunk,
+    /// The chunk read from the backend does not match its hash.
+    Corrupt,
+    /// The GC counts `gc` references to the hash, but the snapshots hold `actual`.
+    WrongRefcount { gc: i64, actual: i64 },
+}
+
+/// A problem with one hash, and the snapshot it was found in (if any).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub snapshot: Option<(String, i64)>,
+    pub hash: hash::Hash,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if let Some((ref family, id)) = self.snapshot {
+            try!(write!(f, "{}#{}: ", family, id));
+        }
+        try!(write!(f, "{}: ", self.hash.bytes.to_hex()));
+        match self.kind {
+            ProblemKind::MissingHash => write!(f, "not in hash index"),
+            ProblemKind::Uncommitted => write!(f, "never committed"),
+            ProblemKind::MissingChildren => write!(f, "tree node without children"),
+            ProblemKind::UnknownBlob(ref name) => {
+                write!(f, "stored in unknown blob {}", name.to_hex())
+            }
+            ProblemKind::Unreadable(ref e) => write!(f, "unreadable: {}", e),
+            ProblemKind::MissingChunk => write!(f, "missing from storage"),
+            ProblemKind::Corrupt => write!(f, "data does not match hash"),
+            ProblemKind::WrongRefcount { gc, actual } => {
+                write!(f, "reference count is {}, should be {}", gc, actual)
+            }
+        }
+    }
+}
+
+/// The result of checking a repository.
+#[derive(Clone, Debug, Default)]
+pub struct CheckReport {
+    /// Committed snapshots that were checked.
+    pub snapshots: usize,
+    /// Distinct hashes that were checked.
+    pub hashes: usize,
+    /// Chunks that were downloaded and hashed again.
+    pub chunks_read: usize,
+    /// Whether the reference counts were checked. They are not when some directory could not
+    /// be read, as the references below it are unknown.
+    pub refcounts_checked: bool,
+    pub problems: Vec<Problem>,
+}
+
+struct Checker<'a, B: StoreBackend + 'a> {
+    hat: &'a HatRc<B>,
+    read_data: bool,
+    blobs: HashSet<Vec<u8>>,
+    // Hashes checked so far, and whether they were fine.
+    checked: HashMap<hash::Hash, bool>,
+    // References to each hash from the snapshots, as the GC should count them.
+    references: HashMap<gc::Id, i64>,
+    snapshot: Option<(String, i64)>,
+    // Whether a directory could not be listed, so that references below it are not counted.
+    incomplete: bool,
+    report: CheckReport,
+}
+
+impl<'a, B: StoreBackend> Checker<'a, B> {
+    fn problem(&mut self, hash: &hash::Hash, kind: ProblemKind) {
+        self.report.problems.push(Problem {
+            snapshot: self.snapshot.clone(),
+            hash: hash.clone(),
+            kind: kind,
+        });
+    }
+
+    fn reference(&mut self, hash: &hash::Hash) {
+        if let Some(id) = self.hat.hash_index.get_id(hash) {
+            *self.references.entry(id).or_insert(0) += 1;
+        }
+    }
+
+    /// Check the hash tree below `hash`: every node must be known to the hash index and stored
+    /// in a known blob. With `read`, every node is also downloaded and hashed again.
+    fn check_tree(&mut self, hash: &hash::Hash, read: bool) -> bool {
+        if let Some(&ok) = self.checked.get(hash) {
+            return ok;
+        }
+        let ok = self.check_node(hash, read);
+        self.checked.insert(hash.clone(), ok);
+        ok
+    }
+
+    fn check_node(&mut self, hash: &hash::Hash, read: bool) -> bool {
+        self.report.hashes += 1;
+        let entry = match self.hat.hash_index.get_id(hash).and_then(|id| {
+            self.hat.hash_index.get_hash(id)
+        }) {
+            Some(entry) => entry,
+            None => {
+                self.problem(hash, ProblemKind::MissingHash);
+                return false;
+            }
+        };
+        let pref = match entry.persistent_ref {
+            Some(pref) => pref,
+            None => {
+                self.problem(hash, ProblemKind::Uncommitted);
+                return false;
+            }
+        };
+
+        // Empty chunks are not stored anywhere.
+        let mut ok = true;
+        if pref.length > 0 && !self.blobs.contains(&pref.blob_id) {
+            self.problem(hash, ProblemKind::UnknownBlob(pref.blob_id.clone()));
+            ok = false;
+        } else if read {
+            ok = self.read_chunk(hash, &pref);
+        }
+
+        if entry.level > 0 {
+            match entry.payload {
+                Some(payload) => {
+                    for child in hash::tree::decode_metadata_refs(&payload[..]) {
+                        ok = self.check_tree(&hash::Hash { bytes: child }, read) && ok;
+                    }
+                }
+                None => {
+                    self.problem(hash, ProblemKind::MissingChildren);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+
+    fn read_chunk(&mut self, hash: &hash::Hash, pref: &blob::ChunkRef) -> bool {
+        self.report.chunks_read += 1;
+        match self.hat.blob_store.retrieve(pref) {
+            Ok(Some(data)) => {
+                if self.hat.blob_store.keeper().hash(&data[..]) == *hash {
+                    return true;
+                }
+                self.problem(hash, ProblemKind::Corrupt);
+            }
+            Ok(None) => self.problem(hash, ProblemKind::MissingChunk),
+            Err(e) => self.problem(hash, ProblemKind::Unreadable(e)),
+        }
+        false
+    }
+
+    /// Check a directory and everything below it. Directory listings are always read, as they
+    /// are needed to find the rest of the snapshot.
+    fn check_dir(&mut self,
+                 family: &Family<B>,
+                 dir_hash: &hash::Hash,
+                 dir_ref: blob::ChunkRef)
+                 -> Result<(), HatError> {
+        if !self.check_tree(dir_hash, true) {
+            self.incomplete = true;
+            return Ok(());
+        }
+        let listing = match family.fetch_dir_data(dir_hash, dir_ref, self.hat.hash_backend()) {
+            Ok(listing) => listing,
+            Err(e) => {
+                self.problem(dir_hash, ProblemKind::Unreadable(e.to_string()));
+                self.incomplete = true;
+                return Ok(());
+            }
+        };
+        for (entry, hash, pref) in listing.into_iter() {
+            self.reference(&hash);
+            if entry.data_hash.is_some() {
+                let read = self.read_data;
+                self.check_tree(&hash, read);
+            } else {
+                // Listed for every reference, to count the references below it.
+                try!(self.check_dir(family, &hash, pref));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Check that every committed snapshot can be read back: all hashes it refers to must be
+    /// in the hash index and stored in blobs known to the blob index, and the GC must count the
+    /// references to them correctly (this is only checked when all directories could be read).
+    /// With `read_data`, all data is downloaded and hashed again; otherwise only the directory
+    /// listings are.
+    pub fn check(&mut self, read_data: bool) -> Result<CheckReport, HatError> {
+        // Chunks still waiting in the current blob have no committed hash yet; store them, so
+        // they are not reported as uncommitted.
+        try!(self.blob_store.flush());
+        let snapshots = self.snapshot_index.list_all();
+
+        let mut checker = Checker {
+            hat: self,
+            read_data: read_data,
+            blobs: self.blob_store.known_blobs(),
+            checked: HashMap::new(),
+            references: HashMap::new(),
+            snapshot: None,
+            incomplete: false,
+            report: CheckReport::default(),
+        };
+        for snapshot in snapshots.into_iter() {
+            let (dir_hash, tree_ref) = match (snapshot.hash, snapshot.tree_ref) {
+                (Some(h), Some(r)) => (h, r),
+                _ => continue,  // Not committed, so not registered either.
+            };
+            let dir_ref = try!(blob::ChunkRef::from_bytes(&mut &tree_ref[..]));
+            let family = try!(self.open_family(snapshot.family_name.clone()));
+
+            checker.snapshot = Some((snapshot.family_name, snapshot.info.snapshot_id));
+            checker.report.snapshots += 1;
+            checker.reference(&dir_hash);
+            try!(checker.check_dir(&family, &dir_hash, dir_ref));
+        }
+        checker.snapshot = None;
+        if checker.incomplete {
+            return Ok(checker.report);
+        }
+
+        checker.report.refcounts_checked = true;
+        self.count_snapshot_lists(&mut checker.references);
+        for entry in self.hash_index.list().into_iter() {
+            let id = try!(self.hash_index
+                .get_id(&entry.hash)
+                .ok_or("Listed hash not found in hash index"));
+            let actual = checker.references.get(&id).cloned().unwrap_or(0);
+            let counted = try!(self.gc.refcount(id));
+            if counted != actual {
+                checker.problem(&entry.hash,
+                                ProblemKind::WrongRefcount {
+                                    gc: counted,
+                                    actual: actual,
+                                });
+            }
+        }
+
+        Ok(checker.report)
+    }
+}
@@ -24,25 +24,15 @@ use blob;
 use hash;
 use key;
 use root_capnp;
-use util::{FileIterator, FnBox, PathHandler};
+use util::{ChunkSizes, FileIterator, FnBox, PathHandler};
 use errors::HatError;
 use hat::insert_path_handler::InsertPathHandler;
 
-fn try_a_few_times_then_panic<F>(mut f: F, msg: &str)
-    where F: FnMut() -> bool
-{
-    for _ in 1 as i32..5 {
-        if f() {
-            return;
-        }
-    }
-    panic!(msg.to_owned());
-}
-
 pub struct Family<B> {
     pub name: String,
     pub key_store: key::Store<B>,
     pub key_store_process: key::StoreProcess<FileIterator, B>,
+    pub chunk_sizes: ChunkSizes,
 }
 impl<B: StoreBackend> Clone for Family<B> {
     fn clone(&self) -> Family<B> {
@@ -50,13 +40,14 @@ impl<B: StoreBackend> Clone for Family<B> {
             name: self.name.clone(),
             key_store: self.key_store.clone(),
             key_store_process: self.key_store_process.clone(),
+            chunk_sizes: self.chunk_sizes,
         }
     }
 }
 
 impl<B: StoreBackend> Family<B> {
     pub fn snapshot_dir(&self, dir: PathBuf) {
-        let handler = InsertPathHandler::new(self.key_store_process.clone());
+        let handler = InsertPathHandler::new(self.key_store_process.clone(), self.chunk_sizes);
         handler.recurse(PathBuf::from(&dir), None);
     }
 
@@ -84,13 +75,13 @@ impl<B: StoreBackend> Family<B> {
     }
 
   pub fn write_file_chunks<HTB: hash::tree::HashTreeBackend<Err=key::MsgError>>(
-    &self, fd: &mut fs::File, tree: hash::tree::ReaderResult<HTB>)
+    &self, fd: &mut fs::File, mut tree: hash::tree::ReaderResult<HTB>) -> Result<(), HatError>
   {
-        for chunk in tree {
-            try_a_few_times_then_panic(|| fd.write_all(&chunk[..]).is_ok(),
-                                       "Could not write chunk.");
+        while let Some(chunk) = try!(tree.try_next()) {
+            try!(fd.write_all(&chunk[..]));
         }
-        try_a_few_times_then_panic(|| fd.flush().is_ok(), "Could not flush file.");
+        try!(fd.flush());
+        Ok(())
     }
 
     pub fn checkout_in_dir(&self,
@@ -105,14 +96,14 @@ impl<B: StoreBackend> Family<B> {
             match read_fn_opt {
                 None => {
                     // This is a directory, recurse!
-                    fs::create_dir_all(&path).unwrap();
+                    try!(fs::create_dir_all(&path));
                     try!(self.checkout_in_dir(path.clone(), entry.id));
                 }
                 Some(read_fn) => {
                     // This is a file, write it
-                    let mut fd = fs::File::create(&path).unwrap();
+                    let mut fd = try!(fs::File::create(&path));
                     if let Some(tree) = try!(read_fn.init()) {
-                        self.write_file_chunks(&mut fd, tree);
+                        try!(self.write_file_chunks(&mut fd, tree));
                     }
                 }
             }
@@ -140,10 +131,10 @@ impl<B: StoreBackend> Family<B> {
          backend: HTB)
          -> Result<Vec<(key::Entry, hash::Hash, blob::ChunkRef)>, HatError> {
         let mut out = Vec::new();
-        let it = try!(hash::tree::SimpleHashTreeReader::open(backend, dir_hash, Some(dir_ref)))
+        let mut it = try!(hash::tree::SimpleHashTreeReader::open(backend, dir_hash, Some(dir_ref)))
             .expect("unable to open dir");
 
-        for chunk in it {
+        while let Some(chunk) = try!(it.try_next()) {
             if chunk.is_empty() {
                 continue;
             }
@@ -209,22 +200,26 @@ impl<B: StoreBackend> Family<B> {
         Ok(out)
     }
 
+    /// Commit the key index to a tree of directory listings. Returns its top hash and
+    /// persistent reference, and the total size of the files in it.
     pub fn commit(&mut self,
                   hash_ch: &mpsc::Sender<hash::Hash>)
-                  -> Result<(hash::Hash, blob::ChunkRef), HatError> {
+                  -> Result<(hash::Hash, blob::ChunkRef, u64), HatError> {
         let mut top_tree = self.key_store.hash_tree_writer();
-        try!(self.commit_to_tree(&mut top_tree, None, hash_ch));
+        let size = try!(self.commit_to_tree(&mut top_tree, None, hash_ch));
 
-        Ok(try!(top_tree.hash()))
+        let (hash, top_ref) = try!(top_tree.hash());
+        Ok((hash, top_ref, size))
     }
 
     pub fn commit_to_tree(&mut self,
                           tree: &mut hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>>,
                           dir_id: Option<u64>,
                           hash_ch: &mpsc::Sender<hash::Hash>)
-                          -> Result<(), HatError> {
+                          -> Result<u64, HatError> {
 
         let files_at_a_time = 1024;
+        let mut size = 0;
         let mut it = try!(self.list_from_key_store(dir_id)).into_iter();
 
         loop {
@@ -262,6 +257,8 @@ impl<B: StoreBackend> Family<B> {
                     }
 
                     if let Some(hash_bytes) = entry.data_hash {
+                        size += entry.data_length.unwrap_or(0);
+
                         // This is a file, store its data hash:
                         let mut hash_ref_msg = capnp::message::Builder::new_default();
                         let mut hash_ref_root =
@@ -281,7 +278,7 @@ impl<B: StoreBackend> Family<B> {
 
                         // This is a directory, recurse!
                         let mut inner_tree = self.key_store.hash_tree_writer();
-                        try!(self.commit_to_tree(&mut inner_tree, entry.id, hash_ch));
+                        size += try!(self.commit_to_tree(&mut inner_tree, entry.id, hash_ch));
                         // Store a reference for the sub-tree in our tree:
                         let (dir_hash, dir_ref) = try!(inner_tree.hash());
 
@@ -313,6 +310,6 @@ impl<B: StoreBackend> Family<B> {
             }
         }
 
-        Ok(())
+        Ok(size)
     }
 }
@@ -23,7 +23,7 @@ use time;
 
 use backend::StoreBackend;
 use key;
-use util::{FileIterator, PathHandler};
+use util::{ChunkSizes, FileIterator, PathHandler};
 
 struct FileEntry {
     key_entry: key::Entry,
@@ -77,14 +77,18 @@ pub struct InsertPathHandler<B: StoreBackend> {
     count: atomic::AtomicIsize,
     last_print: Mutex<time::Timespec>,
     key_store: Mutex<key::StoreProcess<FileIterator, B>>,
+    chunk_sizes: ChunkSizes,
 }
 
 impl<B: StoreBackend> InsertPathHandler<B> {
-    pub fn new(key_store: key::StoreProcess<FileIterator, B>) -> InsertPathHandler<B> {
+    pub fn new(key_store: key::StoreProcess<FileIterator, B>,
+               chunk_sizes: ChunkSizes)
+               -> InsertPathHandler<B> {
         InsertPathHandler {
             count: atomic::AtomicIsize::new(0),
             last_print: Mutex::new(time::now().to_timespec()),
             key_store: Mutex::new(key_store),
+            chunk_sizes: chunk_sizes,
         }
     }
 }
@@ -121,6 +125,7 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
                 let is_directory = file_entry.is_directory();
                 let local_root = path.clone();
                 let full_path = file_entry.full_path.clone();
+                let chunk_sizes = self.chunk_sizes;
 
                 match self.key_store
                     .lock()
@@ -130,7 +135,7 @@ impl<B: StoreBackend> PathHandler<Option<u64>> for InsertPathHandler<B> {
                                                      None
                                                  } else {
                                                      Some(Box::new(move |()| {
-                            match FileIterator::new(&full_path) {
+                            match FileIterator::new(&full_path, &chunk_sizes) {
                                 Err(e) => {
                                     println!("Skipping '{}': {}",
                                              local_root.display(),
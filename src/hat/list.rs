@@ -0,0 +1,169 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Describe the snapshots in a repository.
+
+use std::collections::HashMap;
+
+use backend::StoreBackend;
+use blob;
+use errors::HatError;
+use hash;
+use snapshot;
+
+use super::{Family, HatRc};
+
+
+/// A snapshot, as shown by `hatbin list`.
+#[derive(Clone, Debug)]
+pub struct SnapshotListing {
+    pub family_name: String,
+    pub id: i64,
+    /// One of "committing", "complete", "deleting", "deleted" and "recovering".
+    pub status: &'static str,
+    /// When the snapshot was taken, in seconds since the epoch.
+    pub created: Option<i64>,
+    pub msg: Option<String>,
+    /// The host and path the snapshot was taken from.
+    pub host: Option<String>,
+    pub path: Option<String>,
+    /// The total size of the files in the snapshot.
+    pub size: Option<u64>,
+    /// The stored size of the chunks that no other snapshot refers to, which is what deleting
+    /// the snapshot would free.
+    pub unique_size: Option<u64>,
+}
+
+fn status_name(status: &snapshot::WorkStatus) -> &'static str {
+    match *status {
+        snapshot::WorkStatus::CommitInProgress => "committing",
+        snapshot::WorkStatus::CommitComplete => "complete",
+        snapshot::WorkStatus::DeleteInProgress => "deleting",
+        snapshot::WorkStatus::DeleteComplete => "deleted",
+        snapshot::WorkStatus::RecoverInProgress => "recovering",
+    }
+}
+
+impl<B: StoreBackend> HatRc<B> {
+    /// Describe all snapshots, or those of one family, ordered by family and id.
+    ///
+    /// Finding the unique size of a snapshot means reading the directory listings of all
+    /// snapshots, also those of other families.
+    pub fn list_snapshots(&mut self,
+                          family_name: Option<&str>)
+                          -> Result<Vec<SnapshotListing>, HatError> {
+        let mut snapshots = self.snapshot_index.list_all();
+        snapshots.sort_by(|a, b| {
+            (&a.family_name, a.info.snapshot_id).cmp(&(&b.family_name, b.info.snapshot_id))
+        });
+
+        // For every chunk: the number of snapshots that refer to it, the first of them and its
+        // stored size.
+        let mut chunks: HashMap<hash::Hash, (usize, usize, u64)> = HashMap::new();
+        let mut readable = vec![false; snapshots.len()];
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let (dir_hash, tree_ref) = match (&snapshot.hash, &snapshot.tree_ref) {
+                (&Some(ref h), &Some(ref r)) => (h.clone(), r),
+                _ => continue,  // Not committed, so it does not hold any data yet.
+            };
+            let dir_ref = try!(blob::ChunkRef::from_bytes(&mut &tree_ref[..]));
+            let family = try!(self.open_family(snapshot.family_name.clone()));
+
+            let mut own = HashMap::new();
+            if let Err(e) = self.dir_chunks(&family, &dir_hash, dir_ref, &mut own) {
+                warn!("Could not read snapshot {} of family '{}': {}",
+                      snapshot.info.snapshot_id,
+                      snapshot.family_name,
+                      e);
+                continue;
+            }
+            readable[i] = true;
+            for (hash, length) in own.into_iter() {
+                chunks.entry(hash).or_insert((0, i, length)).0 += 1;
+            }
+        }
+
+        let mut unique = vec![0; snapshots.len()];
+        for &(count, first, length) in chunks.values() {
+            if count == 1 {
+                unique[first] += length;
+            }
+        }
+
+        Ok(snapshots.into_iter()
+            .enumerate()
+            .filter(|&(_, ref s)| family_name.map_or(true, |name| s.family_name == name))
+            .map(|(i, s)| {
+                SnapshotListing {
+                    status: status_name(&s.status),
+                    id: s.info.snapshot_id,
+                    family_name: s.family_name,
+                    created: s.created,
+                    msg: s.msg,
+                    host: s.host,
+                    path: s.path,
+                    size: s.size,
+                    unique_size: if readable[i] { Some(unique[i]) } else { None },
+                }
+            })
+            .collect())
+    }
+
+    /// Add the chunks of a directory and everything below it to `out`, with their stored sizes.
+    fn dir_chunks(&self,
+                  family: &Family<B>,
+                  dir_hash: &hash::Hash,
+                  dir_ref: blob::ChunkRef,
+                  out: &mut HashMap<hash::Hash, u64>)
+                  -> Result<(), HatError> {
+        if out.contains_key(dir_hash) {
+            return Ok(());  // Seen before, along with everything below it.
+        }
+        try!(self.tree_chunks(dir_hash, out));
+        for (entry, hash, pref) in
+            try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend())) {
+            if entry.data_hash.is_some() {
+                try!(self.tree_chunks(&hash, out));
+            } else {
+                try!(self.dir_chunks(family, &hash, pref, out));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the chunks of a hash tree to `out`, with their stored sizes. Only the hash index is
+    /// used, as it knows the children of every tree branch.
+    fn tree_chunks(&self,
+                   hash: &hash::Hash,
+                   out: &mut HashMap<hash::Hash, u64>)
+                   -> Result<(), HatError> {
+        if out.contains_key(hash) {
+            return Ok(());
+        }
+        let entry = try!(self.hash_index
+            .get_id(hash)
+            .and_then(|id| self.hash_index.get_hash(id))
+            .ok_or("Snapshot hash not found in hash index"));
+        let length = entry.persistent_ref.map_or(0, |pref| pref.length as u64);
+        out.insert(hash.clone(), length);
+
+        if entry.level > 0 {
+            let payload = try!(entry.payload.ok_or("Tree branch without children"));
+            for child in hash::tree::decode_metadata_refs(&payload[..]).into_iter() {
+                try!(self.tree_chunks(&hash::Hash { bytes: child }, out));
+            }
+        }
+        Ok(())
+    }
+}
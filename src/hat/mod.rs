@@ -12,16 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{self, Path, PathBuf};
 use std::str;
 use std::sync::{Arc, mpsc};
 use std::thread;
 use capnp;
+use rustc_serialize::hex::ToHex;
 use void::Void;
 
 use backend::StoreBackend;
 use blob;
+use crypto;
 use errors::HatError;
 use gc::{self, Gc, GcRc};
 use hash;
@@ -31,10 +34,18 @@ use snapshot;
 use tags;
 use util::Process;
 
+mod check;
 mod family;
 mod insert_path_handler;
+mod list;
 use self::family::Family;
 
+pub use blob::{Codec, Reconciliation};
+pub use self::check::{CheckReport, Problem, ProblemKind};
+pub use self::list::SnapshotListing;
+pub use snapshot::Description as SnapshotDescription;
+pub use util::{ChunkSizes, Glob};
+
 #[cfg(test)]
 mod tests;
 #[cfg(all(test, feature = "benchmarks"))]
@@ -42,6 +53,54 @@ mod benchmarks;
 
 
 
+/// Restricts a checkout to part of a snapshot. All paths are relative to the snapshot root.
+#[derive(Clone, Debug, Default)]
+pub struct CheckoutFilter {
+    /// Only check out this file or directory. Only the directories above it are read.
+    pub path: Option<PathBuf>,
+    /// Only check out files that match one of these, or all files if there are none.
+    pub include: Vec<Glob>,
+    /// Skip the files and directories that match any of these.
+    pub exclude: Vec<Glob>,
+}
+
+/// The names along `path` from the snapshot root. A leading `/` and `.` refer to the root, so
+/// `/` or `.` alone ask for all of it; an empty path and `..` are rejected.
+fn path_names(path: &Path) -> Result<Vec<String>, HatError> {
+    let mut names = Vec::new();
+    let mut root = false;
+    for component in path.components() {
+        match component {
+            path::Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+            path::Component::RootDir |
+            path::Component::CurDir => root = true,
+            _ => {
+                return Err(From::from(format!("'{}' is not a path within the snapshot",
+                                              path.display())))
+            }
+        }
+    }
+    if names.is_empty() && !root {
+        return Err(From::from("Empty path; use '/' for the whole snapshot"));
+    }
+    Ok(names)
+}
+
+impl CheckoutFilter {
+    fn excludes(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        self.exclude.iter().any(|glob| glob.matches(&path))
+    }
+
+    fn wants_file(&self, path: &Path) -> bool {
+        let included = {
+            let path = path.to_string_lossy();
+            self.include.is_empty() || self.include.iter().any(|glob| glob.matches(&path))
+        };
+        included && !self.excludes(path)
+    }
+}
+
 pub struct GcBackend {
     hash_index: Arc<hash::HashIndex>,
 }
@@ -121,8 +180,27 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     blob_store: Arc<blob::BlobStore<B>>,
     hash_index: Arc<hash::HashIndex>,
     gc: G,
+    chunk_sizes: ChunkSizes,
+    crash_points: CrashPoints,
 }
 
+/// Flush points passed so far, and the one to simulate a crash at (for testing resume).
+#[cfg(test)]
+#[derive(Default)]
+struct CrashPoints {
+    passed: usize,
+    crash_at: Option<usize>,
+}
+
+/// Crashes are only simulated in tests.
+#[cfg(not(test))]
+#[derive(Default)]
+struct CrashPoints;
+
+/// Prefix of the error returned when a simulated crash happens.
+#[cfg(test)]
+const SIMULATED_CRASH: &'static str = "Simulated crash";
+
 pub type HatRc<B> = Hat<B, GcRc<GcBackend>>;
 
 fn concat_filename(mut a: PathBuf, b: &str) -> String {
@@ -142,6 +220,108 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+fn key_file_name(root: PathBuf) -> PathBuf {
+    let mut path = root;
+    path.push("blob.key");
+    path
+}
+
+/// Present while the reference counts may be wrong, after the indexes were rebuilt.
+fn stale_refcounts_name(root: PathBuf) -> PathBuf {
+    let mut path = root;
+    path.push("refcounts.stale");
+    path
+}
+
+fn load_keyring<B: StoreBackend>(backend: &B) -> Result<crypto::KeyRing, HatError> {
+    match try!(crypto::KeyRing::load(backend)) {
+        Some(ring) => Ok(ring),
+        None => Err(From::from("Repository has no keys; run 'key init' first")),
+    }
+}
+
+fn unlock_keyring(ring: &crypto::KeyRing,
+                  passphrase: &str)
+                  -> Result<crypto::Secrets, HatError> {
+    match ring.unlock(passphrase.as_bytes()) {
+        Some((_id, secrets)) => Ok(secrets),
+        None => Err(From::from("Could not unlock any repository key with the given passphrase")),
+    }
+}
+
+/// Unlock the keys of the repository. `passphrase` is only asked for when there is a key ring.
+fn unlock_keeper<B, F>(repository_root: PathBuf,
+                       backend: &B,
+                       passphrase: F)
+                       -> Result<crypto::Keeper, HatError>
+    where B: StoreBackend,
+          F: FnOnce() -> Option<String>
+{
+    match try!(crypto::KeyRing::load(backend)) {
+        Some(ring) => {
+            let passphrase = try!(passphrase().ok_or("Repository is encrypted, but no passphrase \
+                                                    was given"));
+            let secrets = try!(unlock_keyring(&ring, &passphrase));
+            Ok(crypto::Keeper::from_secrets(&secrets))
+        }
+        None => {
+            // Repositories from before the key ring keep their key in a local file.
+            match try!(crypto::Keeper::read_key_file(&key_file_name(repository_root))) {
+                Some(key) => Ok(crypto::Keeper::new(key)),
+                None if try!(is_plaintext_repository(backend)) => {
+                    Ok(crypto::Keeper::plaintext())
+                }
+                None => Err(From::from("Repository has no keys; run 'key init' first")),
+            }
+        }
+    }
+}
+
+/// Whether the repository was written before encryption. Such repositories have no keys, but
+/// do have the single snapshot list that only those versions stored, and that reads without
+/// keys. Anything less, like local indexes, is no evidence: a missing key ring must not make new
+/// data go out unencrypted.
+fn is_plaintext_repository<B: StoreBackend>(backend: &B) -> Result<bool, HatError> {
+    match try!(backend.retrieve(LEGACY_ROOT_NAME.as_bytes())) {
+        Some(bytes) => Ok(parse_snapshot_list(&bytes[..], &mut Vec::new()).is_ok()),
+        None => Ok(false),
+    }
+}
+
+const SETTINGS_NAME: &'static str = "settings";
+
+fn codec_from_settings(bytes: &[u8]) -> Result<blob::Codec, capnp::Error> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                            capnp::message::ReaderOptions::new()));
+    let root = try!(reader.get_root::<root_capnp::settings::Reader>());
+    Ok(match try!(root.get_compression().which()) {
+        root_capnp::settings::compression::None(()) => blob::Codec::None,
+        root_capnp::settings::compression::Zstd(()) => blob::Codec::Zstd,
+    })
+}
+
+fn codec_to_settings(codec: blob::Codec) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::settings::Builder>();
+        match codec {
+            blob::Codec::None => root.init_compression().set_none(()),
+            blob::Codec::Zstd => root.init_compression().set_zstd(()),
+        }
+    }
+    let mut bytes = Vec::new();
+    capnp::serialize_packed::write_message(&mut bytes, &message).unwrap();
+    bytes
+}
+
+/// The compression used for new data in the repository. Defaults to zstd.
+fn load_codec<B: StoreBackend>(blob_store: &blob::BlobStore<B>) -> Result<blob::Codec, HatError> {
+    match try!(blob_store.retrieve_named(SETTINGS_NAME)) {
+        Some(bytes) => Ok(try!(codec_from_settings(&bytes[..]))),
+        None => Ok(blob::Codec::Zstd),
+    }
+}
+
 struct SnapshotLister<'a, B: StoreBackend> {
     backend: &'a key::HashStoreBackend<B>,
     family: &'a Family<B>,
@@ -196,11 +376,209 @@ fn list_snapshot<'a, B: StoreBackend>(backend: &'a key::HashStoreBackend<B>,
     }
 }
 
+/// Make sure the hash index knows `entry`, and the blob index the blob it is stored in.
+fn recover_entry<B: StoreBackend>(hashes: &hash::HashIndex,
+                                  blobs: &blob::BlobStore<B>,
+                                  entry: &hash::Entry)
+                                  -> Result<i64, HatError> {
+    let pref = entry.persistent_ref.clone().unwrap();
+
+    // Make sure we have the blob described.
+    blobs.recover(pref.clone());
+
+    // Now insert the hash information if needed.
+    let id = match hashes.reserve(entry) {
+        hash::ReserveResult::HashKnown(id) => id,
+        hash::ReserveResult::ReserveOk(id) => {
+            // Commit hash.
+            hashes.commit(&entry.hash, pref);
+            id
+        }
+    };
+
+    Ok(id)
+}
+
+/// List the hashes below the top of a hash tree. Returns the payload and level of the top.
+fn recover_tree<B: hash::tree::HashTreeBackend<Err = key::MsgError>>
+    (backend: B,
+     hash: &hash::Hash,
+     pref: blob::ChunkRef,
+     out: &mut Vec<hash::Entry>)
+     -> Result<(Option<Vec<u8>>, i64), HatError> {
+    match try!(hash::tree::SimpleHashTreeReader::open(backend, &hash, Some(pref)))
+        .unwrap() {
+        hash::tree::ReaderResult::Empty |
+        hash::tree::ReaderResult::SingleBlock(..) => Ok((None, 0)),
+        hash::tree::ReaderResult::Tree(mut reader) => {
+            let (payload, level) = try!(reader.list_entries(out));
+            Ok((Some(payload), level))
+        }
+    }
+}
+
+/// The level of a tree branch found while rebuilding the index: one above its children. The
+/// levels of branches that were found already are kept in `levels`.
+fn branch_level(hash: &hash::Hash,
+                branches: &HashMap<hash::Hash, (blob::ChunkRef, Vec<(hash::Hash, blob::ChunkRef)>)>,
+                hashes: &hash::HashIndex,
+                levels: &mut HashMap<hash::Hash, i64>)
+                -> Option<i64> {
+    if let Some(&level) = levels.get(hash) {
+        return Some(level);
+    }
+    let child_level = match branches.get(hash).and_then(|&(_, ref children)| children.first()) {
+        None => return None,
+        Some(&(_, blob::ChunkRef { kind: blob::Kind::TreeLeaf, .. })) => Some(0),
+        Some(&(ref child, _)) => {
+            match branch_level(child, branches, hashes, levels) {
+                Some(level) => Some(level),
+                // The child may be known from before.
+                None => hashes.get_id(child).and_then(|id| hashes.get_hash(id)).map(|e| e.level),
+            }
+        }
+    };
+    child_level.map(|child_level| {
+        levels.insert(hash.clone(), child_level + 1);
+        child_level + 1
+    })
+}
+
+/// Number of generations of the snapshot list kept in external storage.
+const ROOT_GENERATIONS: u64 = 3;
+
+/// Snapshots per chunk of the snapshot list hash tree.
+const SNAPSHOTS_PER_CHUNK: usize = 1024;
+
+/// The single snapshot list stored by older versions.
+const LEGACY_ROOT_NAME: &'static str = "root";
+
+fn root_name(slot: u64) -> String {
+    format!("root.{}", slot)
+}
+
+/// A root pointing to a generation of the snapshot list.
+struct Root {
+    slot: u64,
+    generation: u64,
+    hash: hash::Hash,
+    tree_ref: blob::ChunkRef,
+}
+
+fn root_from_bytes(slot: u64, bytes: &[u8]) -> Result<Root, HatError> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                            capnp::message::ReaderOptions::new()));
+    let root = try!(reader.get_root::<root_capnp::snapshot_list_root::Reader>());
+    Ok(Root {
+        slot: slot,
+        generation: root.get_generation(),
+        hash: hash::Hash { bytes: try!(root.get_hash()).to_owned() },
+        tree_ref: try!(blob::ChunkRef::from_bytes(&mut try!(root.get_tree_reference()))),
+    })
+}
+
+fn root_to_bytes(generation: u64, hash: &hash::Hash, tree_ref: &blob::ChunkRef) -> Vec<u8> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let mut root = message.init_root::<root_capnp::snapshot_list_root::Builder>();
+        root.set_generation(generation);
+        root.set_hash(&hash.bytes[..]);
+        root.set_tree_reference(&tree_ref.as_bytes()[..]);
+    }
+    let mut bytes = Vec::new();
+    capnp::serialize_packed::write_message(&mut bytes, &message).unwrap();
+    bytes
+}
+
+/// A snapshot as listed in the stored snapshot list.
+struct ListedSnapshot {
+    id: i64,
+    family_name: String,
+    description: snapshot::Description,
+    created: Option<i64>,
+    hash: Vec<u8>,
+    tree_ref: blob::ChunkRef,
+}
+
+/// Lists store unknown texts as empty.
+fn non_empty(text: &str) -> Option<String> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_owned())
+    }
+}
+
+fn snapshot_list_to_bytes(snapshots: &[snapshot::Status]) -> Result<Vec<u8>, HatError> {
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let root = message.init_root::<root_capnp::snapshot_list::Builder>();
+        let mut list = root.init_snapshots(snapshots.len() as u32);
+
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let mut s = list.borrow().get(i as u32);
+            s.set_id(snapshot.info.snapshot_id);
+            s.set_family_name(&snapshot.family_name);
+            s.set_msg(snapshot.msg.as_ref().map_or("", |m| &m[..]));
+            s.set_host(snapshot.host.as_ref().map_or("", |h| &h[..]));
+            s.set_path(snapshot.path.as_ref().map_or("", |p| &p[..]));
+            match snapshot.created {
+                None => s.borrow().init_created().set_unknown(()),
+                Some(ts) => s.borrow().init_created().set_timestamp(ts),
+            }
+            s.set_hash(&snapshot.hash.as_ref().unwrap().bytes);
+            s.set_tree_reference(&snapshot.tree_ref.as_ref().unwrap()[..]);
+        }
+    }
+    let mut listing = Vec::new();
+    try!(capnp::serialize_packed::write_message(&mut listing, &message));
+    Ok(listing)
+}
+
+fn parse_snapshot_list(bytes: &[u8], out: &mut Vec<ListedSnapshot>) -> Result<(), HatError> {
+    let reader = try!(capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                            capnp::message::ReaderOptions::new()));
+    let list = try!(reader.get_root::<root_capnp::snapshot_list::Reader>());
+    for s in try!(list.get_snapshots()).iter() {
+        out.push(ListedSnapshot {
+            id: s.get_id(),
+            family_name: try!(s.get_family_name()).to_owned(),
+            description: snapshot::Description {
+                msg: non_empty(try!(s.get_msg())),
+                host: non_empty(try!(s.get_host())),
+                path: non_empty(try!(s.get_path())),
+            },
+            created: match s.get_created().which() {
+                Ok(root_capnp::snapshot::created::Timestamp(ts)) => Some(ts),
+                _ => None,
+            },
+            hash: try!(s.get_hash()).to_owned(),
+            tree_ref: try!(blob::ChunkRef::from_bytes(&mut try!(s.get_tree_reference()))),
+        });
+    }
+    Ok(())
+}
+
 impl<B: StoreBackend> HatRc<B> {
-    pub fn open_repository(repository_root: PathBuf,
-                           backend: Arc<B>,
-                           max_blob_size: usize)
-                           -> Result<HatRc<B>, HatError> {
+    /// Open the repository kept in `backend`, with local indexes in `repository_root`.
+    /// `passphrase` is only called when the repository has a key ring to unlock.
+    pub fn open_repository<F>(repository_root: PathBuf,
+                              backend: Arc<B>,
+                              max_blob_size: usize,
+                              passphrase: F)
+                              -> Result<HatRc<B>, HatError>
+        where F: FnOnce() -> Option<String>
+    {
+        let keeper = try!(unlock_keeper(repository_root.clone(), &*backend, passphrase));
+        HatRc::open_with_keeper(repository_root, backend, max_blob_size, keeper)
+    }
+
+    fn open_with_keeper(repository_root: PathBuf,
+                        backend: Arc<B>,
+                        max_blob_size: usize,
+                        keeper: crypto::Keeper)
+                        -> Result<HatRc<B>, HatError> {
+        let keeper = Arc::new(keeper);
         let snapshot_index_path = snapshot_index_name(repository_root.clone());
         let blob_index_path = blob_index_name(repository_root.clone());
         let hash_index_path = hash_index_name(repository_root.clone());
@@ -208,7 +586,8 @@ impl<B: StoreBackend> HatRc<B> {
         let bi_p = Arc::new(try!(blob::BlobIndex::new(&blob_index_path)));
         let hi_p = Arc::new(try!(hash::HashIndex::new(&hash_index_path)));
 
-        let bs_p = Arc::new(blob::BlobStore::new(bi_p, backend, max_blob_size));
+        let bs_p = Arc::new(blob::BlobStore::new(bi_p, backend, keeper, max_blob_size));
+        bs_p.set_codec(try!(load_codec(&bs_p)));
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -219,6 +598,8 @@ impl<B: StoreBackend> HatRc<B> {
             hash_index: hi_p.clone(),
             blob_store: bs_p.clone(),
             gc: gc,
+            chunk_sizes: ChunkSizes::default(),
+            crash_points: CrashPoints::default(),
         };
 
         // Resume any unfinished commands.
@@ -232,8 +613,9 @@ impl<B: StoreBackend> HatRc<B> {
         let si_p = snapshot::SnapshotIndex::new_for_testing().unwrap();
         let bi_p = Arc::new(blob::BlobIndex::new_for_testing().unwrap());
         let hi_p = Arc::new(hash::HashIndex::new_for_testing().unwrap());
+        let keeper = Arc::new(crypto::Keeper::new_for_testing());
 
-        let bs_p = Arc::new(blob::BlobStore::new(bi_p, backend, max_blob_size));
+        let bs_p = Arc::new(blob::BlobStore::new(bi_p, backend, keeper, max_blob_size));
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -244,6 +626,8 @@ impl<B: StoreBackend> HatRc<B> {
             hash_index: hi_p.clone(),
             blob_store: bs_p.clone(),
             gc: gc,
+            chunk_sizes: ChunkSizes::default(),
+            crash_points: CrashPoints::default(),
         };
 
         // Resume any unfinished commands.
@@ -252,6 +636,96 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(hat)
     }
 
+    /// Create the key ring of a new repository, protected by `passphrase`.
+    /// A key left in a local key file by an older version is moved into the key ring.
+    /// Returns the id of the first key.
+    pub fn init_keys(repository_root: PathBuf,
+                     backend: Arc<B>,
+                     passphrase: String)
+                     -> Result<u64, HatError> {
+        if try!(crypto::KeyRing::load(&*backend)).is_some() {
+            return Err(From::from("Repository already has keys"));
+        }
+
+        let key_path = key_file_name(repository_root);
+        let secrets = match try!(crypto::Keeper::read_key_file(&key_path)) {
+            Some(key) => {
+                // Existing data was hashed without a key; keep doing so.
+                crypto::Secrets {
+                    blob_key: key,
+                    hash_key: None,
+                }
+            }
+            None => {
+                // Chunks do not record whether they are sealed, so plaintext data can not be
+                // mixed with encrypted data.
+                if try!(is_plaintext_repository(&*backend)) {
+                    return Err(From::from("Repository holds unencrypted data from an older \
+                                           version; create a new repository to use keys"));
+                }
+                crypto::Secrets::generate()
+            }
+        };
+
+        let ring = try!(crypto::KeyRing::new(&secrets, passphrase.as_bytes()));
+        try!(ring.store(&*backend));
+
+        // The key ring now holds the only copy of the key.
+        if key_path.exists() {
+            try!(fs::remove_file(&key_path));
+        }
+
+        Ok(ring.ids()[0])
+    }
+
+    /// Let `new_passphrase` unlock the repository. Returns the id of the new key.
+    pub fn add_key(backend: Arc<B>,
+                   passphrase: String,
+                   new_passphrase: String)
+                   -> Result<u64, HatError> {
+        let mut ring = try!(load_keyring(&*backend));
+        let secrets = try!(unlock_keyring(&ring, &passphrase));
+        let id = try!(ring.add(&secrets, new_passphrase.as_bytes()));
+        try!(ring.store(&*backend));
+
+        Ok(id)
+    }
+
+    /// Remove the key with the given id. `passphrase` must unlock one of the keys.
+    pub fn remove_key(backend: Arc<B>, passphrase: String, id: u64) -> Result<(), HatError> {
+        let mut ring = try!(load_keyring(&*backend));
+        try!(unlock_keyring(&ring, &passphrase));
+        try!(ring.remove(id));
+        try!(ring.store(&*backend));
+
+        Ok(())
+    }
+
+    /// Replace the passphrase of the key unlocked by `passphrase`.
+    /// Returns the id of the changed key.
+    pub fn change_passphrase(backend: Arc<B>,
+                             passphrase: String,
+                             new_passphrase: String)
+                             -> Result<u64, HatError> {
+        let mut ring = try!(load_keyring(&*backend));
+        let id = try!(ring.change_passphrase(passphrase.as_bytes(), new_passphrase.as_bytes()));
+        try!(ring.store(&*backend));
+
+        Ok(id)
+    }
+
+    /// The compression used for new data in the repository.
+    pub fn compression(&self) -> Result<blob::Codec, HatError> {
+        load_codec(&self.blob_store)
+    }
+
+    /// Change the compression used for new data in the repository. Existing data is left as is.
+    pub fn set_compression(&mut self, codec: blob::Codec) -> Result<(), HatError> {
+        try!(self.blob_store.store_named(SETTINGS_NAME, &codec_to_settings(codec)[..]));
+        self.blob_store.set_codec(codec);
+        Ok(())
+    }
+
     pub fn hash_tree_writer(&self) -> hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>> {
         hash::tree::SimpleHashTreeWriter::new(8, self.hash_backend())
     }
@@ -280,93 +754,322 @@ impl<B: StoreBackend> HatRc<B> {
             name: name,
             key_store: ks,
             key_store_process: ks_p,
+            chunk_sizes: self.chunk_sizes,
         })
     }
 
-    pub fn meta_commit(&mut self) -> Result<(), HatError> {
-        let all_snapshots = self.snapshot_index.list_all();
+    /// Set the chunk sizes used for files snapshotted by families opened from now on.
+    pub fn set_chunk_sizes(&mut self, sizes: ChunkSizes) {
+        self.chunk_sizes = sizes;
+    }
 
-        // TODO(jos): use a hash tree for this listing.
-        let mut message = capnp::message::Builder::new_default();
+    /// Store the list of committed snapshots in external storage, for `recover`. This is done
+    /// after every commit and delete, and does nothing if the list did not change.
+    ///
+    /// The list is written as a hash tree, and a small root pointing to it is stored in one of
+    /// `ROOT_GENERATIONS` slots. The new root replaces the oldest one, so a crash while storing
+    /// it, or a corrupt latest root, leaves the previous generations to recover from.
+    pub fn meta_commit(&mut self) -> Result<(), HatError> {
+        let (roots, _) = self.read_roots();
+        let generation = roots.first().map_or(1, |r| r.generation + 1);
+        let slot = generation % ROOT_GENERATIONS;
+
+        // Keep the snapshot list from being garbage collected while a root refers to it.
+        let (hash, tree_ref) = try!(self.write_snapshot_list());
+        try!(self.flush_blob_store());
+        if roots.first().map_or(false, |r| r.hash == hash) {
+            // The latest root lists the same snapshots already.
+            return Ok(());
+        }
+        let id = try!(self.hash_index.get_id(&hash).ok_or("Snapshot list hash does not exist"));
+        let count = try!(self.gc.refcount(id));
+        try!(self.gc.set_refcount(id, count + 1));
+        self.hash_index.flush();
 
-        {
-            let root = message.init_root::<root_capnp::snapshot_list::Builder>();
-            let mut snapshots = root.init_snapshots(all_snapshots.len() as u32);
+        // The root is overwritten in place. If that fails, nothing refers to the new list, and the
+        // next attempt counts it again.
+        let root = root_to_bytes(generation, &hash, &tree_ref);
+        if let Err(e) = self.blob_store.store_named(&root_name(slot), &root[..]) {
+            try!(self.gc.set_refcount(id, count));
+            self.hash_index.flush();
+            return Err(From::from(e));
+        }
 
-            for (i, snapshot) in all_snapshots.into_iter().enumerate() {
-                let mut s = snapshots.borrow().get(i as u32);
-                s.set_id(snapshot.info.snapshot_id);
-                s.set_family_name(&snapshot.family_name);
-                s.set_msg(&snapshot.msg.unwrap_or("".to_owned()));
-                s.set_hash(&snapshot.hash.unwrap().bytes);
-                s.set_tree_reference(&snapshot.tree_ref.unwrap());
+        // Release the snapshot list of the generation that was replaced.
+        if let Some(old) = roots.iter().find(|r| r.slot == slot) {
+            if let Some(id) = self.hash_index.get_id(&old.hash) {
+                let count = try!(self.gc.refcount(id));
+                if count > 0 {
+                    try!(self.gc.set_refcount(id, count - 1));
+                }
             }
         }
-        let mut listing = Vec::new();
-        capnp::serialize_packed::write_message(&mut listing, &message).unwrap();
+        self.hash_index.flush();
 
-        // TODO(jos): make sure this operation is atomic or resumable.
-        try!(self.blob_store.store_named("root", listing.as_slice()));
         Ok(())
     }
 
-    pub fn recover(&mut self) -> Result<(), HatError> {
-        let root = match try!(self.blob_store.retrieve_named("root")) {
-            Some(r) => r,
-            _ => return Err(From::from("Could not read root file")),
+    /// Run `meta_commit` after a commit or delete, unless snapshots are still being recovered
+    /// and the list would be incomplete.
+    fn auto_meta_commit(&mut self) -> Result<(), HatError> {
+        let recovering = self.snapshot_index.list_not_done().into_iter().any(|s| match s.status {
+            snapshot::WorkStatus::RecoverInProgress => true,
+            _ => false,
+        });
+        if recovering {
+            return Ok(());
+        }
+        self.meta_commit()
+    }
+
+    /// Write the list of committed snapshots as a hash tree.
+    fn write_snapshot_list(&mut self) -> Result<(hash::Hash, blob::ChunkRef), HatError> {
+        let committed: Vec<_> = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| match s.status {
+                snapshot::WorkStatus::CommitComplete => s.hash.is_some() && s.tree_ref.is_some(),
+                _ => false,
+            })
+            .collect();
+
+        let mut tree = self.hash_tree_writer();
+        for chunk in committed.chunks(SNAPSHOTS_PER_CHUNK) {
+            try!(tree.append(try!(snapshot_list_to_bytes(chunk))));
+        }
+
+        Ok(try!(tree.hash()))
+    }
+
+    /// Read all root slots. Returns the roots that could be read, newest first, and the slots
+    /// that hold an object at all.
+    fn read_roots(&self) -> (Vec<Root>, Vec<u64>) {
+        let mut roots = Vec::new();
+        let mut used = Vec::new();
+        for slot in 0..ROOT_GENERATIONS {
+            let name = root_name(slot);
+            let root = match self.blob_store.retrieve_named(&name) {
+                Ok(None) => continue,
+                Ok(Some(bytes)) => root_from_bytes(slot, &bytes[..]),
+                Err(e) => Err(From::from(e)),
+            };
+            used.push(slot);
+            match root {
+                Ok(root) => roots.push(root),
+                Err(e) => warn!("Ignoring unreadable root '{}': {}", name, e),
+            }
+        }
+        roots.sort_by(|a, b| b.generation.cmp(&a.generation));
+        (roots, used)
+    }
+
+    /// Read the snapshot list that `root` points to. The hashes of its hash tree are recovered
+    /// as well, so that it is kept until the root is replaced.
+    fn recover_snapshot_list(&self, root: &Root) -> Result<Vec<ListedSnapshot>, HatError> {
+        let mut snapshots = Vec::new();
+        let reader = try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(),
+                                                                 &root.hash,
+                                                                 Some(root.tree_ref.clone())));
+        if let Some(mut reader) = reader {
+            while let Some(chunk) = try!(reader.try_next()) {
+                if !chunk.is_empty() {
+                    try!(parse_snapshot_list(&chunk[..], &mut snapshots));
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        let (payload, level) = try!(recover_tree(self.hash_backend(),
+                                                 &root.hash,
+                                                 root.tree_ref.clone(),
+                                                 &mut entries));
+        entries.push(hash::Entry {
+            hash: root.hash.clone(),
+            persistent_ref: Some(root.tree_ref.clone()),
+            level: level,
+            payload: payload,
+        });
+        for entry in entries.iter() {
+            try!(recover_entry(&self.hash_index, &self.blob_store, entry));
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Restore the snapshot index from the newest readable snapshot list in the backend. Returns
+    /// the number of reference counts that had to be corrected afterwards.
+    pub fn recover(&mut self) -> Result<usize, HatError> {
+        // Use the newest snapshot list that can be read.
+        let mut listed = None;
+        for root in self.read_roots().0.iter() {
+            match self.recover_snapshot_list(root) {
+                Ok(snapshots) => {
+                    // The root refers to the list, as after `meta_commit`.
+                    let id = try!(self.hash_index
+                        .get_id(&root.hash)
+                        .ok_or("Snapshot list hash does not exist"));
+                    let count = try!(self.gc.refcount(id));
+                    try!(self.gc.set_refcount(id, count + 1));
+                    if listed.is_none() {
+                        listed = Some(snapshots);
+                    }
+                }
+                Err(e) => {
+                    warn!("Snapshot list of generation {} is unreadable: {}",
+                          root.generation,
+                          e)
+                }
+            }
+        }
+        let listed = match listed {
+            Some(snapshots) => snapshots,
+            None => {
+                // Repositories from before the generations keep a single list.
+                match try!(self.blob_store.retrieve_named(LEGACY_ROOT_NAME)) {
+                    Some(bytes) => {
+                        let mut snapshots = Vec::new();
+                        try!(parse_snapshot_list(&bytes[..], &mut snapshots));
+                        snapshots
+                    }
+                    None => return Err(From::from("Could not read any snapshot list")),
+                }
+            }
         };
-        let message_reader =
-            capnp::serialize_packed::read_message(&mut &root[..],
-                                                  capnp::message::ReaderOptions::new())
-                .unwrap();
-        let snapshot_list = message_reader.get_root::<root_capnp::snapshot_list::Reader>().unwrap();
-
-        for s in snapshot_list.get_snapshots().unwrap().iter() {
-            let tree_ref = blob::ChunkRef::from_bytes(&mut s.get_tree_reference().unwrap())
-                .unwrap();
-            self.snapshot_index
-                .recover(s.get_id(),
-                         s.get_family_name()
-                             .unwrap(),
-                         s.get_msg().unwrap(),
-                         s.get_hash().unwrap(),
-                         &tree_ref,
-                         Some(snapshot::WorkStatus::RecoverInProgress));
+
+        let mut families = BTreeSet::new();
+        for s in listed.into_iter() {
+            self.snapshot_index.recover(s.id,
+                                        &s.family_name,
+                                        &s.description,
+                                        s.created,
+                                        &s.hash[..],
+                                        &s.tree_ref,
+                                        Some(snapshot::WorkStatus::RecoverInProgress));
+            families.insert(s.family_name);
         }
         self.flush_snapshot_index();
         try!(self.resume());
 
+        // Snapshots recovered above were registered with the GC one by one. Recount all
+        // references, in case some snapshots were registered already.
+        let corrected = try!(self.rebuild_refcounts());
+
+        // Let the next snapshot of each family reuse the data of its latest snapshot.
+        for family_name in families.into_iter() {
+            try!(self.recover_keys(family_name));
+        }
+
+        Ok(corrected)
+    }
+
+    /// Rebuild the key index of a family from its latest snapshot.
+    fn recover_keys(&mut self, family_name: String) -> Result<(), HatError> {
+        let (dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
+            Some(snapshot::Status { hash: Some(h), tree_ref: Some(r), .. }) => {
+                (h, try!(blob::ChunkRef::from_bytes(&mut &r[..])))
+            }
+            _ => return Ok(()),
+        };
+        let family = try!(self.open_family(family_name));
+        try!(self.recover_key_dir(&family, None, &dir_hash, dir_ref));
+        family.flush()
+    }
+
+    fn recover_key_dir(&self,
+                       family: &Family<B>,
+                       parent: Option<u64>,
+                       dir_hash: &hash::Hash,
+                       dir_ref: blob::ChunkRef)
+                       -> Result<(), HatError> {
+        for (entry, hash, pref) in
+            try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend())) {
+            let entry = key::Entry { parent_id: parent, ..entry };
+            if entry.data_hash.is_some() {
+                try!(family.key_store.recover(entry, Some((hash, pref))));
+            } else {
+                let id = try!(family.key_store.recover(entry, None));
+                try!(self.recover_key_dir(family, Some(id), &hash, pref));
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the references from all snapshots to each hash, and correct the reference counts
+    /// kept by the GC where they differ. Returns the number of corrected counts.
+    pub fn rebuild_refcounts(&mut self) -> Result<usize, HatError> {
+        let mut expected = HashMap::new();
+        for snapshot in self.snapshot_index.list_all().into_iter() {
+            let (dir_hash, tree_ref) = match (snapshot.hash, snapshot.tree_ref) {
+                (Some(h), Some(r)) => (h, r),
+                _ => continue,  // Not committed, so not registered either.
+            };
+            let dir_ref = try!(blob::ChunkRef::from_bytes(&mut &tree_ref[..]));
+            let family = try!(self.open_family(snapshot.family_name));
+            let hash_backend = self.hash_backend();
+            for hash in list_snapshot(&hash_backend, &family, dir_hash, dir_ref) {
+                let id = try!(self.hash_index
+                    .get_id(&try!(hash))
+                    .ok_or("Snapshot hash not found in hash index"));
+                *expected.entry(id).or_insert(0) += 1;
+            }
+        }
+
+        self.count_snapshot_lists(&mut expected);
+
+        let mut corrected = 0;
+        for entry in self.hash_index.list().into_iter() {
+            let id = try!(self.hash_index
+                .get_id(&entry.hash)
+                .ok_or("Listed hash not found in hash index"));
+            let count = expected.get(&id).cloned().unwrap_or(0);
+            if try!(self.gc.refcount(id)) != count {
+                try!(self.gc.set_refcount(id, count));
+                corrected += 1;
+            }
+        }
+        self.hash_index.flush();
+        try!(self.set_refcounts_stale(false));
+
+        Ok(corrected)
+    }
+
+    /// Whether the reference counts may be wrong, so that the GC must not rely on them.
+    fn refcounts_stale(&self) -> bool {
+        match self.repository_root {
+            Some(ref root) => stale_refcounts_name(root.clone()).exists(),
+            None => false,
+        }
+    }
+
+    fn set_refcounts_stale(&self, stale: bool) -> Result<(), HatError> {
+        let path = match self.repository_root {
+            Some(ref root) => stale_refcounts_name(root.clone()),
+            None => return Ok(()),
+        };
+        if stale {
+            try!(fs::File::create(&path));
+        } else if path.exists() {
+            try!(fs::remove_file(&path));
+        }
         Ok(())
     }
 
+    /// Count the references from the roots to their snapshot lists, which are kept while a root
+    /// refers to them.
+    fn count_snapshot_lists(&self, expected: &mut HashMap<gc::Id, i64>) {
+        for root in self.read_roots().0.iter() {
+            if let Some(id) = self.hash_index.get_id(&root.hash) {
+                *expected.entry(id).or_insert(0) += 1;
+            }
+        }
+    }
+
     fn recover_snapshot(&mut self,
                         family_name: String,
                         info: snapshot::Info,
                         hash: &hash::Hash,
                         dir_ref: blob::ChunkRef)
                         -> Result<(), HatError> {
-        fn recover_entry<B: StoreBackend>(hashes: &hash::HashIndex,
-                                          blobs: &blob::BlobStore<B>,
-                                          entry: &hash::Entry)
-                                          -> Result<i64, HatError> {
-            let pref = entry.persistent_ref.clone().unwrap();
-
-            // Make sure we have the blob described.
-            blobs.recover(pref.clone());
-
-            // Now insert the hash information if needed.
-            let id = match hashes.reserve(entry) {
-                hash::ReserveResult::HashKnown(id) => id,
-                hash::ReserveResult::ReserveOk(id) => {
-                    // Commit hash.
-                    hashes.commit(&entry.hash, pref);
-                    id
-                }
-            };
-
-            Ok(id)
-        }
-
         let family = self.open_family(family_name.clone())
             .expect(&format!("Could not open family '{}'", family_name));
         let mut registered = Vec::new();
@@ -393,7 +1096,7 @@ impl<B: StoreBackend> HatRc<B> {
         });
 
         try!(self.gc.register(&info, id_receiver));
-        self.flush_blob_store();
+        try!(self.flush_blob_store());
 
         // Recover final root hash for the snapshot.
         try!(recover_entry(&self.hash_index,
@@ -420,22 +1123,6 @@ impl<B: StoreBackend> HatRc<B> {
                        register_out: &mut Vec<hash::Entry>,
                        recover_out: &mut Vec<hash::Entry>)
                        -> Result<(Option<Vec<u8>>, i64), HatError> {
-        fn recover_tree<B: hash::tree::HashTreeBackend<Err = key::MsgError>>
-            (backend: B,
-             hash: &hash::Hash,
-             pref: blob::ChunkRef,
-             out: &mut Vec<hash::Entry>)
-             -> Result<(Option<Vec<u8>>, i64), HatError> {
-            match try!(hash::tree::SimpleHashTreeReader::open(backend, &hash, Some(pref)))
-                .unwrap() {
-                hash::tree::ReaderResult::Empty |
-                hash::tree::ReaderResult::SingleBlock(..) => Ok((None, 0)),
-                hash::tree::ReaderResult::Tree(mut reader) => {
-                    let (payload, level) = try!(reader.list_entries(out));
-                    Ok((Some(payload), level))
-                }
-            }
-        }
         for (file, hash, pref) in
             try!(family.fetch_dir_data(dir_hash, dir_ref.clone(), self.hash_backend())) {
             let (payload, level) = match file.data_hash {
@@ -584,6 +1271,15 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Commit a new snapshot of `family`, with `description` recorded in the snapshot list.
+    pub fn commit_described(&mut self,
+                            family: &Family<B>,
+                            description: &snapshot::Description)
+                            -> Result<(), HatError> {
+        let snap_info = self.snapshot_index.reserve(family.name.clone(), description);
+        self.commit(family, Some(snap_info))
+    }
+
     pub fn commit(&mut self,
                   family: &Family<B>,
                   resume_info: Option<snapshot::Info>)
@@ -596,10 +1292,11 @@ impl<B: StoreBackend> HatRc<B> {
             Some(info) => info,  // Resume already started commit.
             None => {
                 // Create new commit.
-                self.snapshot_index.reserve(family.name.clone())
+                self.snapshot_index.reserve(family.name.clone(), &Default::default())
             }
         };
         self.flush_snapshot_index();
+        try!(self.flush_point("commit reserved"));
 
         // Prepare.
         let (hash_sender, hash_receiver) = mpsc::channel();
@@ -615,7 +1312,7 @@ impl<B: StoreBackend> HatRc<B> {
         });
 
         // Commit metadata while registering needed data-hashes (files and dirs).
-        let (hash, top_ref) = {
+        let (hash, top_ref, size) = {
             let mut local_family: Family<B> = (*family).clone();
             let (s, r) = mpsc::channel();
 
@@ -627,13 +1324,15 @@ impl<B: StoreBackend> HatRc<B> {
 
         // Push any remaining data to external storage.
         // This also flushes our hashes from the memory index, so we can tag them.
-        self.flush_blob_store();
+        try!(self.flush_blob_store());
+        try!(self.flush_point("commit registered"));
 
         // Tag 2:
         // We update the snapshot entry with the tree hash, which we then register.
         // When the GC has seen the final hash, we flush everything so far.
-        self.snapshot_index.update(&snap_info, &hash, &top_ref);
+        self.snapshot_index.update(&snap_info, &hash, &top_ref, size);
         self.flush_snapshot_index();
+        try!(self.flush_point("commit updated"));
 
         // Register the final hash.
         // At this point, the GC should still be able to either resume or rollback safely.
@@ -642,6 +1341,7 @@ impl<B: StoreBackend> HatRc<B> {
         let hash_id = self.hash_index.get_id(&hash).expect("Hash does not exist");
         try!(self.gc.register_final(&snap_info, hash_id));
         try!(family.flush());
+        try!(self.flush_point("commit registered final"));
         try!(self.commit_finalize(family, snap_info, &hash));
 
         Ok(())
@@ -666,10 +1366,17 @@ impl<B: StoreBackend> HatRc<B> {
         // Commit locally. Let the GC perform any needed cleanup.
         self.snapshot_index.ready_commit(&snap_info);
         self.flush_snapshot_index();
+        try!(self.flush_point("commit ready"));
 
         let hash_id = self.hash_index.get_id(hash).expect("Hash does not exist");
         try!(self.gc.register_cleanup(&snap_info, hash_id));
         try!(family.flush());
+        try!(self.flush_point("commit cleaned up"));
+
+        // List the snapshot externally before marking it done, so that resume does it again
+        // if we crash first.
+        try!(self.auto_meta_commit());
+        try!(self.flush_point("commit listed"));
 
         // Tag 0: All is done.
         self.snapshot_index.commit(&snap_info);
@@ -678,60 +1385,199 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Simulate a crash at the `n`th flush point from now (counting from 1), or never.
+    #[cfg(test)]
+    pub fn crash_at(&mut self, n: Option<usize>) {
+        self.crash_points = CrashPoints {
+            passed: 0,
+            crash_at: n,
+        };
+    }
+
+    /// Mark a point in a multi-step operation where everything done so far has been flushed,
+    /// so that a crash here must be resumable.
+    #[cfg(test)]
+    fn flush_point(&mut self, name: &str) -> Result<(), HatError> {
+        let points = &mut self.crash_points;
+        points.passed += 1;
+        if points.crash_at == Some(points.passed) {
+            return Err(From::from(format!("{} at flush point {} ({})",
+                                          SIMULATED_CRASH,
+                                          points.passed,
+                                          name)));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(test))]
+    fn flush_point(&mut self, _name: &str) -> Result<(), HatError> {
+        Ok(())
+    }
+
     pub fn flush_snapshot_index(&mut self) {
         self.snapshot_index.flush();
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        Ok(try!(self.blob_store.flush()))
+    }
+
+    /// The id of the newest complete snapshot of a family, optionally only among those taken
+    /// before `time` (in seconds since the epoch).
+    pub fn latest_complete(&mut self,
+                           family_name: &str,
+                           before: Option<i64>)
+                           -> Result<i64, HatError> {
+        let newest = self.snapshot_index
+            .list_all()
+            .into_iter()
+            .filter(|s| s.family_name == family_name)
+            .filter(|s| match s.status {
+                snapshot::WorkStatus::CommitComplete => true,
+                _ => false,
+            })
+            .filter(|s| before.map_or(true, |t| s.created.map_or(false, |c| c < t)))
+            .map(|s| s.info.snapshot_id)
+            .max();
+        match (newest, before) {
+            (Some(id), _) => Ok(id),
+            (None, None) => {
+                Err(From::from(format!("Family '{}' has no complete snapshot", family_name)))
+            }
+            (None, Some(t)) => {
+                Err(From::from(format!("Family '{}' has no complete snapshot from before {}",
+                                       family_name,
+                                       t)))
+            }
+        }
     }
 
+    /// Check out the snapshot `snapshot_id` of a family, or its latest complete snapshot.
     pub fn checkout_in_dir(&mut self,
                            family_name: String,
+                           snapshot_id: Option<i64>,
                            output_dir: PathBuf)
                            -> Result<(), HatError> {
-        // Extract latest snapshot info:
-        let (_info, dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
-            Some((i, h, Some(r))) => (i, h, r),
-            _ => {
-                panic!("Tried to checkout family '{}' before first completed commit",
-                       family_name)
+        self.checkout_filtered_in_dir(family_name, snapshot_id, output_dir, &Default::default())
+    }
+
+    /// Like `checkout_in_dir`, but only check out what `filter` selects. Files are written to
+    /// the same place below `output_dir` as in a full checkout.
+    pub fn checkout_filtered_in_dir(&mut self,
+                                    family_name: String,
+                                    snapshot_id: Option<i64>,
+                                    output_dir: PathBuf,
+                                    filter: &CheckoutFilter)
+                                    -> Result<(), HatError> {
+        let snapshot_id = match snapshot_id {
+            Some(id) => id,
+            None => try!(self.latest_complete(&family_name, None)),
+        };
+        let (dir_hash, tree_ref) = match self.snapshot_index.lookup(&family_name, snapshot_id) {
+            None => {
+                return Err(From::from(format!("Snapshot {} of family '{}' does not exist",
+                                              snapshot_id,
+                                              family_name)))
+            }
+            Some(snapshot::Status { status: snapshot::WorkStatus::CommitComplete,
+                                    hash: Some(h),
+                                    tree_ref: Some(r),
+                                    .. }) => (h, r),
+            Some(_) => {
+                return Err(From::from(format!("Snapshot {} of family '{}' is not complete",
+                                              snapshot_id,
+                                              family_name)))
             }
         };
+        let mut dir_ref = try!(blob::ChunkRef::from_bytes(&mut &tree_ref[..]));
+        let mut dir_hash = dir_hash;
 
-        let family = self.open_family(family_name.clone())
-            .expect(&format!("Could not open family '{}'", family_name));
+        let family = try!(self.open_family(family_name));
+
+        // Follow the path down, reading only the directories along it.
+        let names = match filter.path {
+            Some(ref path) => try!(path_names(path)),
+            None => Vec::new(),
+        };
+        let mut relative = PathBuf::new();
+        for (i, name) in names.iter().enumerate() {
+            let found = try!(family.fetch_dir_data(&dir_hash, dir_ref, self.hash_backend()))
+                .into_iter()
+                .find(|&(ref entry, _, _)| entry.name == name.as_bytes());
+            relative.push(name);
+            let (entry, hash, pref) = match found {
+                Some(found) => found,
+                None => {
+                    return Err(From::from(format!("'{}' is not in the snapshot",
+                                                  relative.display())))
+                }
+            };
+            if entry.data_hash.is_some() {
+                if i + 1 < names.len() {
+                    return Err(From::from(format!("'{}' is not a directory",
+                                                  relative.display())));
+                }
+                if filter.wants_file(&relative) {
+                    try!(self.checkout_file(&family, &output_dir.join(&relative), &hash, pref));
+                }
+                return Ok(());
+            }
+            if filter.excludes(&relative) {
+                return Ok(());
+            }
+            dir_hash = hash;
+            dir_ref = pref;
+        }
 
-        let mut output_dir = output_dir;
-        self.checkout_dir_ref(&family, &mut output_dir, &dir_hash, dir_ref)
+        self.checkout_dir_ref(&family, filter, &output_dir, &mut relative, &dir_hash, dir_ref)
     }
 
     fn checkout_dir_ref(&self,
                         family: &Family<B>,
-                        output: &mut PathBuf,
+                        filter: &CheckoutFilter,
+                        output_dir: &Path,
+                        relative: &mut PathBuf,
                         dir_hash: &hash::Hash,
                         dir_ref: blob::ChunkRef)
                         -> Result<(), HatError> {
-        fs::create_dir_all(&output).unwrap();
+        // With include patterns, only the directories of included files are created.
+        if filter.include.is_empty() {
+            let output = output_dir.join(&relative);
+            println!("{}", output.display());
+            try!(fs::create_dir_all(&output));
+        }
         for (entry, hash, pref) in
             try!(family.fetch_dir_data(dir_hash, dir_ref, self.hash_backend())) {
             assert!(entry.name.len() > 0);
 
-            output.push(str::from_utf8(&entry.name[..]).unwrap());
-            println!("{}", output.display());
-
+            relative.push(str::from_utf8(&entry.name[..]).unwrap());
             if entry.data_hash.is_some() {
-                let mut fd = fs::File::create(&output).unwrap();
-                let tree_opt = try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(),
-                                                                           &hash,
-                                                                           Some(pref)));
-                if let Some(tree) = tree_opt {
-                    family.write_file_chunks(&mut fd, tree);
+                if filter.wants_file(relative) {
+                    try!(self.checkout_file(family, &output_dir.join(&relative), &hash, pref));
                 }
-            } else {
-                try!(self.checkout_dir_ref(family, output, &hash, pref));
+            } else if !filter.excludes(relative) {
+                try!(self.checkout_dir_ref(family, filter, output_dir, relative, &hash, pref));
             }
-            output.pop();
+            relative.pop();
+        }
+        Ok(())
+    }
+
+    fn checkout_file(&self,
+                     family: &Family<B>,
+                     output: &Path,
+                     hash: &hash::Hash,
+                     pref: blob::ChunkRef)
+                     -> Result<(), HatError> {
+        println!("{}", output.display());
+        if let Some(dir) = output.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        let mut fd = try!(fs::File::create(output));
+        let tree_opt =
+            try!(hash::tree::SimpleHashTreeReader::open(self.hash_backend(), hash, Some(pref)));
+        if let Some(tree) = tree_opt {
+            try!(family.write_file_chunks(&mut fd, tree));
         }
         Ok(())
     }
@@ -749,7 +1595,9 @@ impl<B: StoreBackend> HatRc<B> {
     pub fn deregister(&mut self, family: &Family<B>, snapshot_id: i64) -> Result<(), HatError> {
         let (info, dir_hash, dir_ref) = match self.snapshot_index
             .lookup(&family.name, snapshot_id) {
-            Some((i, h, Some(r))) => (i, h, r),
+            Some(snapshot::Status { info, hash: Some(h), tree_ref: Some(r), .. }) => {
+                (info, h, try!(blob::ChunkRef::from_bytes(&mut &r[..])))
+            }
             _ => {
                 return Err(From::from(format!("No complete snapshot found for family {} with \
                                                id {:?}",
@@ -761,30 +1609,35 @@ impl<B: StoreBackend> HatRc<B> {
         // Make the snapshot to enable resuming.
         self.snapshot_index.will_delete(&info);
         self.flush_snapshot_index();
+        try!(self.flush_point("delete started"));
 
         let final_ref = self.hash_index
             .get_id(&dir_hash)
             .expect("Snapshot hash does not exist");
 
+        // List the snapshot before touching the GC, so that failing to read it leaves the GC
+        // as it was and the delete can be resumed.
+        let mut ids = Vec::new();
         {
             let hash_backend = self.hash_backend();
-            let &mut Hat { ref hash_index, ref mut gc, .. } = self;
-
-            let listing = || {
-                let (id_sender, id_receiver) = mpsc::channel();
-                for hash in list_snapshot(&hash_backend, &family, dir_hash, dir_ref) {
-                    let hash = hash.unwrap();
-                    match hash_index.get_id(&hash) {
-                        Some(id) => id_sender.send(id).unwrap(),
-                        None => panic!("Unexpected reply from hash index."),
-                    }
+            for hash in list_snapshot(&hash_backend, &family, dir_hash, dir_ref) {
+                match self.hash_index.get_id(&try!(hash)) {
+                    Some(id) => ids.push(id),
+                    None => return Err(From::from("Unexpected reply from hash index.")),
                 }
-                id_receiver
-            };
-
-            try!(gc.deregister(&info, final_ref, listing));
+            }
         }
+
+        let listing = move || {
+            let (id_sender, id_receiver) = mpsc::channel();
+            for id in ids.into_iter() {
+                id_sender.send(id).unwrap();
+            }
+            id_receiver
+        };
+        try!(self.gc.deregister(&info, final_ref, listing));
         try!(family.flush());
+        try!(self.flush_point("delete deregistered"));
 
         self.deregister_finalize(family, info, final_ref)
     }
@@ -808,10 +1661,17 @@ impl<B: StoreBackend> HatRc<B> {
         // Mark the snapshot to enable resuming.
         self.snapshot_index.ready_delete(&snap_info);
         self.flush_snapshot_index();
+        try!(self.flush_point("delete ready"));
 
         // Clear GC state.
         try!(self.gc.register_cleanup(&snap_info, final_ref));
         try!(family.flush());
+        try!(self.flush_point("delete cleaned up"));
+
+        // Unlist the snapshot externally before forgetting it, so that resume does it again
+        // if we crash first.
+        try!(self.auto_meta_commit());
+        try!(self.flush_point("delete unlisted"));
 
         // Delete snapshot metadata.
         self.snapshot_index.delete(snap_info);
@@ -823,6 +1683,11 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     pub fn gc(&mut self) -> Result<(i64, i64), HatError> {
+        if self.refcounts_stale() {
+            return Err(From::from("Reference counts are not known since the index was rebuilt; \
+                                   run 'recover' first"));
+        }
+
         // Remove unused hashes.
         let mut deleted_hashes = 0;
         let (sender, receiver) = mpsc::channel();
@@ -846,11 +1711,121 @@ impl<B: StoreBackend> HatRc<B> {
         // Anything still marked "in progress" is not referenced by any hash.
         try!(self.blob_store.delete_by_tag(tags::Tag::InProgress));
         self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
+        try!(self.blob_store.flush());
 
         Ok((deleted_hashes, live_blobs))
     }
 
+    /// Compare the blobs in the backend with the local index: report blobs that nothing refers
+    /// to (and delete them if asked), and blobs that should exist but do not.
+    pub fn reconcile(&mut self, delete: bool) -> Result<Reconciliation, HatError> {
+        try!(self.blob_store.flush());
+
+        let mut live = HashSet::new();
+        for entry in self.hash_index.list().into_iter() {
+            if let Some(pref) = entry.persistent_ref {
+                live.insert(pref.blob_id);
+            }
+        }
+
+        Ok(try!(self.blob_store.reconcile(&live, delete)))
+    }
+
+    /// Rebuild the blob and hash indexes from the blobs in the backend, using the chunk
+    /// references in their footers. Hashes that are known already are kept as they are. Nothing
+    /// refers to the added hashes until the snapshots are recovered, so the GC refuses to run
+    /// until `recover` or `rebuild_refcounts` has counted the references again. Returns the number
+    /// of blobs read and the number of hashes added.
+    pub fn rebuild_index(&mut self) -> Result<(usize, usize), HatError> {
+        try!(self.blob_store.flush());
+        try!(self.set_refcounts_stale(true));
+        let keeper = self.blob_store.keeper();
+
+        // Leaves can be added right away, but branches need the levels of their children.
+        let mut entries = Vec::new();
+        let mut branches = HashMap::new();
+        let mut blobs = 0;
+        for name in try!(self.blob_store.list_stored()).into_iter() {
+            let chunks = match self.blob_store.read_blob(&name[..]) {
+                Ok(Some(chunks)) => chunks,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Skipping unreadable blob {}: {}", name.to_hex(), e);
+                    continue;
+                }
+            };
+            blobs += 1;
+            for (pref, chunk) in chunks.into_iter() {
+                let hash = keeper.hash(&chunk[..]);
+                match pref.kind {
+                    blob::Kind::TreeLeaf => {
+                        entries.push(hash::Entry {
+                            hash: hash,
+                            level: 0,
+                            payload: None,
+                            persistent_ref: Some(pref),
+                        })
+                    }
+                    blob::Kind::TreeBranch => {
+                        match hash::tree::decode_branch(&chunk[..]) {
+                            Ok(children) => {
+                                branches.insert(hash, (pref, children));
+                            }
+                            Err(e) => warn!("Skipping unreadable tree branch: {}", e),
+                        }
+                    }
+                }
+            }
+        }
+
+        // The empty chunk is never stored, but is referred to like any other.
+        entries.push(hash::Entry {
+            hash: keeper.hash(&[]),
+            level: 0,
+            payload: None,
+            persistent_ref: Some(blob::ChunkRef {
+                blob_id: vec![0],
+                offset: 0,
+                length: 0,
+                kind: blob::Kind::TreeLeaf,
+                codec: blob::Codec::None,
+            }),
+        });
+
+        let mut levels = HashMap::new();
+        for (hash, &(ref pref, ref children)) in branches.iter() {
+            let level = match branch_level(hash, &branches, &self.hash_index, &mut levels) {
+                Some(level) => level,
+                None => {
+                    warn!("Skipping tree branch with unknown children: {}", hash.bytes.to_hex());
+                    continue;
+                }
+            };
+            let mut payload = Vec::new();
+            for &(ref child, _) in children.iter() {
+                payload.extend_from_slice(&child.bytes[..]);
+            }
+            entries.push(hash::Entry {
+                hash: hash.clone(),
+                level: level,
+                payload: Some(payload),
+                persistent_ref: Some(pref.clone()),
+            });
+        }
+
+        let mut added = 0;
+        for entry in entries.iter() {
+            if !self.hash_index.hash_exists(&entry.hash) {
+                added += 1;
+            }
+            try!(recover_entry(&self.hash_index, &self.blob_store, entry));
+        }
+        self.hash_index.flush();
+        try!(self.blob_store.flush());
+
+        Ok((blobs, added))
+    }
+
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
         key::HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone())
     }
@@ -12,13 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use backend::{MemoryBackend, StoreBackend};
+use backend::{Fault, FaultyBackend, MemoryBackend, Op, StoreBackend};
+use backend::tests::TempDir;
+use blob;
+use crypto;
 use errors::HatError;
-use hat::HatRc;
+use hash;
+use hat::{CheckoutFilter, ChunkSizes, Glob, HatRc, LEGACY_ROOT_NAME, Problem, ProblemKind,
+          ROOT_GENERATIONS, SIMULATED_CRASH, recover_tree, root_name, snapshot_list_to_bytes};
 use hat::family::Family;
 use key;
+use snapshot;
 use util::FileIterator;
 
 
@@ -53,13 +63,41 @@ pub fn entry(name: Vec<u8>) -> key::Entry {
     }
 }
 
+/// The hashes and blobs of the snapshot lists that the roots refer to.
+fn snapshot_lists<B: StoreBackend>(hat: &HatRc<B>) -> (HashSet<Vec<u8>>, HashSet<Vec<u8>>) {
+    let mut hashes = HashSet::new();
+    let mut blobs = HashSet::new();
+    for root in hat.read_roots().0.into_iter() {
+        let mut entries = Vec::new();
+        recover_tree(hat.hash_backend(), &root.hash, root.tree_ref.clone(), &mut entries)
+            .unwrap();
+        entries.push(hash::Entry {
+            hash: root.hash,
+            persistent_ref: Some(root.tree_ref),
+            level: 0,
+            payload: None,
+        });
+        for entry in entries.into_iter() {
+            let pref = entry.persistent_ref.unwrap();
+            hashes.insert(entry.hash.bytes);
+            // An empty list is not stored in any blob.
+            if pref.length > 0 {
+                blobs.insert(pref.blob_id);
+            }
+        }
+    }
+    (hashes, blobs)
+}
+
 fn snapshot_files<B: StoreBackend>(family: &Family<B>,
                                    files: Vec<(&str, Vec<u8>)>)
                                    -> Result<(), HatError> {
     for (name, contents) in files {
-        try!(family.snapshot_direct(entry(name.bytes().collect()),
-                                    false,
-                                    Some(FileIterator::from_bytes(contents))));
+        let file = key::Entry {
+            data_length: Some(contents.len() as u64),
+            ..entry(name.bytes().collect())
+        };
+        try!(family.snapshot_direct(file, false, Some(FileIterator::from_bytes(contents))));
     }
     Ok(())
 }
@@ -98,10 +136,11 @@ fn snapshot_commit_many_empty_files() {
     assert_eq!(deleted, 0);
     assert!(live > 0);
 
+    // Only the snapshot lists are left.
     hat.deregister(&fam, 1).unwrap();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
-    assert_eq!(live, 0);
+    assert_eq!(live as usize, snapshot_lists(&hat).0.len());
 }
 
 #[test]
@@ -121,10 +160,11 @@ fn snapshot_commit_many_empty_directories() {
     assert_eq!(deleted, 0);
     assert!(live > 0);
 
+    // Only the snapshot lists are left.
     hat.deregister(&fam, 1).unwrap();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
-    assert_eq!(live, 0);
+    assert_eq!(live as usize, snapshot_lists(&hat).0.len());
 }
 
 #[test]
@@ -168,11 +208,11 @@ fn snapshot_reuse_index() {
     assert_eq!(live2, live);
     assert_eq!(deleted2, 0);
 
-    // Cleanup: only 1 snapshot was committed.
+    // Cleanup: only 1 snapshot was committed, so only the snapshot lists are left.
     hat.deregister(&fam, 1).unwrap();
     let (deleted, live) = hat.gc().unwrap();
     assert!(deleted > 0);
-    assert_eq!(live, 0);
+    assert_eq!(live as usize, snapshot_lists(&hat).0.len());
 }
 
 #[test]
@@ -227,5 +267,973 @@ fn recover() {
 
     let (deleted, live3) = hat2.gc().unwrap();
     assert!(deleted > 0);
-    assert_eq!(live3, 0);
+    assert_eq!(live3 as usize, snapshot_lists(&hat2).0.len());
+}
+
+/// The ids of the snapshots in the newest snapshot list.
+fn newest_list<B: StoreBackend>(hat: &HatRc<B>) -> Vec<i64> {
+    let roots = hat.read_roots().0;
+    let mut ids: Vec<_> =
+        hat.recover_snapshot_list(&roots[0]).unwrap().into_iter().map(|s| s.id).collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn commit_and_delete_update_snapshot_list() {
+    let (_, mut hat, fam) = setup_family();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+
+    hat.commit(&fam, None).unwrap();
+    hat.commit(&fam, None).unwrap();
+    assert_eq!(newest_list(&hat), vec![1, 2]);
+
+    hat.deregister(&fam, 1).unwrap();
+    assert_eq!(newest_list(&hat), vec![2]);
+
+    // Nothing changed since, so no new generation is stored.
+    let generation = hat.read_roots().0[0].generation;
+    hat.meta_commit().unwrap();
+    assert_eq!(hat.read_roots().0[0].generation, generation);
+}
+
+#[test]
+fn meta_commit_failure_keeps_refcount() {
+    let repo = Repo::new("meta-commit-failure");
+    commit_files(&repo);
+    let mut hat = repo.open();
+    let list = hat.read_roots().0[0].hash.clone();
+    let id = hat.hash_index.get_id(&list).unwrap();
+    let count = hat.gc.refcount(id).unwrap();
+
+    // Without roots, the same list is stored again, but storing the root fails.
+    for slot in 0..ROOT_GENERATIONS {
+        repo.backend.backend().delete(root_name(slot).as_bytes()).unwrap();
+    }
+    repo.backend.inject_all(Op::Store, Fault::Error);
+    assert!(hat.meta_commit().is_err());
+    assert_eq!(hat.gc.refcount(id).unwrap(), count);
+
+    repo.backend.clear();
+    hat.meta_commit().unwrap();
+    assert_eq!(hat.gc.refcount(id).unwrap(), count + 1);
+}
+
+
+// Fault injection: every test below makes a backend operation fail, drops the hat as a crashed
+// process would, and checks that reopening (which resumes unfinished work) leaves a consistent
+// repository.
+
+const FAMILY: &'static str = "familyname";
+const PASSPHRASE: &'static str = "passphrase";
+
+/// A repository with its indexes on disk, so that it can be reopened.
+struct Repo {
+    dir: TempDir,
+    backend: Arc<FaultyBackend<MemoryBackend>>,
+}
+
+impl Repo {
+    fn new(name: &str) -> Repo {
+        let repo = Repo {
+            dir: TempDir::new(name),
+            backend: Arc::new(FaultyBackend::new(MemoryBackend::new())),
+        };
+        HatRc::init_keys(repo.dir.0.clone(), repo.backend.clone(), PASSPHRASE.to_owned())
+            .unwrap();
+        repo
+    }
+
+    /// A new repository with the same keys as `other`, so that it produces the same hashes.
+    /// Only the key ring is copied, as the roots of `other` list snapshots the new repository
+    /// does not have.
+    fn with_keys_of(name: &str, other: &Repo) -> Repo {
+        let repo = Repo {
+            dir: TempDir::new(name),
+            backend: Arc::new(FaultyBackend::new(MemoryBackend::new())),
+        };
+        crypto::KeyRing::load(other.backend.backend())
+            .unwrap()
+            .unwrap()
+            .store(repo.backend.backend())
+            .unwrap();
+        repo
+    }
+
+    /// A copy of `other` with all its objects and local indexes. Unlike a repository that
+    /// merely has the same keys, it also stores its data in blobs of the same names.
+    fn copy_of(name: &str, other: &Repo) -> Repo {
+        let repo = Repo::with_keys_of(name, other);
+        let keys = repo.backend.backend().list().unwrap();
+        let from = other.backend.backend();
+        for object in from.list().unwrap().into_iter().filter(|o| !keys.contains(o)) {
+            let data = from.retrieve(&object[..]).unwrap().unwrap();
+            repo.backend.backend().store(&object[..], &data[..]).unwrap();
+        }
+        for file in fs::read_dir(&other.dir.0).unwrap() {
+            let file = file.unwrap();
+            fs::copy(file.path(), repo.dir.0.join(file.file_name())).unwrap();
+        }
+        repo
+    }
+
+    fn open(&self) -> HatRc<FaultyBackend<MemoryBackend>> {
+        HatRc::open_repository(self.dir.0.clone(),
+                               self.backend.clone(),
+                               4 * 1024 * 1024,
+                               || Some(PASSPHRASE.to_owned()))
+            .unwrap()
+    }
+}
+
+#[test]
+fn list_snapshots_sizes() {
+    let (_, mut hat, fam) = setup_family();
+    snapshot_files(&fam, vec![("name1", vec![1; 1000]), ("name2", vec![2; 500])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let other = hat.open_family("other".to_owned()).unwrap();
+    snapshot_files(&other, vec![("name3", vec![3; 2000])]).unwrap();
+    other.flush().unwrap();
+    hat.commit(&other, None).unwrap();
+
+    let listed = hat.list_snapshots(None).unwrap();
+    let names: Vec<_> = listed.iter().map(|s| (&s.family_name[..], s.id)).collect();
+    assert_eq!(names, vec![("familyname", 1), ("familyname", 2), ("other", 1)]);
+    for s in listed.iter() {
+        assert_eq!(s.status, "complete");
+        assert!(s.created.is_some());
+    }
+    assert_eq!(listed[0].size, Some(1500));
+    assert_eq!(listed[2].size, Some(2000));
+
+    // The first two snapshots share all their chunks.
+    assert_eq!(listed[0].unique_size, Some(0));
+    assert_eq!(listed[1].unique_size, Some(0));
+    assert!(listed[2].unique_size.unwrap() > 0);
+
+    let listed = hat.list_snapshots(Some("other")).unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].family_name, "other");
+}
+
+fn fault_files() -> Vec<(&'static str, Vec<u8>)> {
+    vec![("name1", vec![1; 100000]), ("name2", vec![2; 200000]), ("name3", vec![3; 1000])]
+}
+
+/// Snapshot and commit `fault_files()`.
+fn commit_files(repo: &Repo) {
+    let mut hat = repo.open();
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    snapshot_files(&fam, fault_files()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+}
+
+/// Check that the latest snapshot holds `files`.
+fn check_checkout<B: StoreBackend>(hat: &mut HatRc<B>, files: Vec<(&str, Vec<u8>)>) {
+    let out = TempDir::new("fault-checkout");
+    hat.checkout_in_dir(FAMILY.to_owned(), None, out.0.clone()).unwrap();
+    for (name, contents) in files.into_iter() {
+        let mut data = Vec::new();
+        fs::File::open(out.0.join(name)).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, contents);
+    }
+}
+
+#[test]
+fn checkout_by_id_and_time() {
+    let (_, mut hat, fam) = setup_family();
+    let name = "familyname".to_owned();
+    let out = TempDir::new("checkout-missing");
+    assert!(hat.checkout_in_dir(name.clone(), None, out.0.clone()).is_err());
+
+    snapshot_files(&fam, vec![("name1", vec![1; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    snapshot_files(&fam, vec![("name2", vec![2; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    // A reserved snapshot is skipped, and cannot be checked out by id.
+    hat.snapshot_index.reserve(name.clone(), &Default::default());
+    let out = TempDir::new("checkout-incomplete");
+    assert!(hat.checkout_in_dir(name.clone(), Some(3), out.0.clone()).is_err());
+    assert!(hat.checkout_in_dir(name.clone(), Some(4), out.0.clone()).is_err());
+
+    let out = TempDir::new("checkout-latest");
+    hat.checkout_in_dir(name.clone(), None, out.0.clone()).unwrap();
+    assert!(out.0.join("name2").exists());
+
+    let out = TempDir::new("checkout-id");
+    hat.checkout_in_dir(name.clone(), Some(1), out.0.clone()).unwrap();
+    assert!(out.0.join("name1").exists());
+    assert!(!out.0.join("name2").exists());
+
+    assert_eq!(hat.latest_complete(&name, Some(i64::max_value())).unwrap(), 2);
+    assert!(hat.latest_complete(&name, Some(0)).is_err());
+}
+
+#[test]
+fn snapshot_with_chunk_sizes() {
+    let src = TempDir::new("chunk-sizes-src");
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..300000)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    fs::File::create(src.0.join("file")).unwrap().write_all(&data[..]).unwrap();
+
+    let snapshot = |sizes: ChunkSizes| {
+        let mut hat = setup_hat(Arc::new(MemoryBackend::new()));
+        hat.set_chunk_sizes(sizes);
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        fam.snapshot_dir(src.0.clone());
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+
+        let out = TempDir::new("chunk-sizes");
+        hat.checkout_in_dir(FAMILY.to_owned(), None, out.0.clone()).unwrap();
+        let mut checked_out = Vec::new();
+        fs::File::open(out.0.join("file")).unwrap().read_to_end(&mut checked_out).unwrap();
+        assert!(checked_out == data);
+        hat.hash_index.list().len()
+    };
+
+    let small = ChunkSizes {
+        min: 256,
+        avg: 1024,
+        max: 4096,
+    };
+    assert!(snapshot(small) > 10 * snapshot(ChunkSizes::default()));
+}
+
+/// Files and directories (ending in '/') below `dir`, sorted.
+fn list_tree(dir: &Path, prefix: &str, out: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.path().is_dir() {
+            out.push(format!("{}/", name));
+            list_tree(&entry.path(), &format!("{}/", name), out);
+        } else {
+            out.push(name);
+        }
+    }
+    out.sort();
+}
+
+#[test]
+fn checkout_path_and_globs() {
+    let (_, mut hat, fam) = setup_family();
+    let src = TempDir::new("checkout-filter-src");
+    let names = ["a.txt", "b.rs", "sub/c.txt", "sub/d.rs", "sub/deep/e.txt", "other/f.txt"];
+    for name in names.iter() {
+        let path = src.0.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::File::create(&path).unwrap().write_all(name.as_bytes()).unwrap();
+    }
+    fam.snapshot_dir(src.0.clone());
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let mut checkout = |path: Option<&str>,
+                        include: &[&str],
+                        exclude: &[&str]|
+                        -> Result<Vec<String>, HatError> {
+        let filter = CheckoutFilter {
+            path: path.map(PathBuf::from),
+            include: include.iter().map(|g| Glob::new(g)).collect(),
+            exclude: exclude.iter().map(|g| Glob::new(g)).collect(),
+        };
+        let out = TempDir::new("checkout-filter");
+        try!(hat.checkout_filtered_in_dir("familyname".to_owned(), None, out.0.clone(), &filter));
+        let mut listed = vec![];
+        list_tree(&out.0, "", &mut listed);
+        Ok(listed)
+    };
+
+    assert_eq!(checkout(Some("sub"), &[], &["deep"]).unwrap(),
+               vec!["sub/", "sub/c.txt", "sub/d.rs"]);
+    assert_eq!(checkout(Some("/sub/deep/e.txt"), &[], &[]).unwrap(),
+               vec!["sub/", "sub/deep/", "sub/deep/e.txt"]);
+    assert_eq!(checkout(None, &["*.rs"], &[]).unwrap(),
+               vec!["b.rs", "sub/", "sub/d.rs"]);
+    assert_eq!(checkout(None, &["*.txt"], &["sub/deep", "a.*"]).unwrap(),
+               vec!["other/", "other/f.txt", "sub/", "sub/c.txt"]);
+
+    assert!(checkout(Some("sub/missing"), &[], &[]).is_err());
+    assert!(checkout(Some("a.txt/b"), &[], &[]).is_err());
+
+    // Paths may not leave the snapshot, and only name its root explicitly.
+    assert!(checkout(Some(".."), &[], &[]).is_err());
+    assert!(checkout(Some("../sub"), &[], &[]).is_err());
+    assert!(checkout(Some("sub/../other"), &[], &[]).is_err());
+    assert!(checkout(Some(""), &[], &[]).is_err());
+    let whole = checkout(None, &[], &[]).unwrap();
+    assert_eq!(checkout(Some("/"), &[], &[]).unwrap(), whole);
+    assert_eq!(checkout(Some("./sub/c.txt"), &[], &[]).unwrap(),
+               vec!["sub/", "sub/c.txt"]);
+}
+
+/// Reopen the repository without faults, and check that after a GC no blob is missing or left
+/// unused, and that the latest snapshot (if `files` is given) holds `files`.
+fn check_consistent(repo: &Repo, files: Option<Vec<(&str, Vec<u8>)>>) {
+    repo.backend.clear();
+    let mut hat = repo.open();
+    hat.gc().unwrap();
+
+    if let Some(files) = files {
+        check_checkout(&mut hat, files);
+    }
+
+    let report = hat.reconcile(false).unwrap();
+    assert_eq!(report.orphans, Vec::<Vec<u8>>::new());
+    assert_eq!(report.missing, Vec::<Vec<u8>>::new());
+}
+
+/// Check that the only blobs left are those of the snapshot lists, which are kept even when all
+/// snapshots are deleted.
+fn check_only_snapshot_lists(repo: &Repo) {
+    let hat = repo.open();
+    let mut names: Vec<_> = repo.backend
+        .backend()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|n| n.len() == blob::BLOB_NAME_LEN)
+        .collect();
+    names.sort();
+    let mut lists: Vec<_> = snapshot_lists(&hat).1.into_iter().collect();
+    lists.sort();
+    assert_eq!(names, lists);
+}
+
+fn count_blobs(repo: &Repo) -> usize {
+    let names = repo.backend.backend().list().unwrap();
+    names.into_iter().filter(|n| n.len() == blob::BLOB_NAME_LEN).count()
+}
+
+#[test]
+fn fault_during_snapshot_flush() {
+    for &fault in [Fault::Error, Fault::PartialWrite].iter() {
+        let repo = Repo::new("fault-flush");
+        {
+            let hat = repo.open();
+            let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+            snapshot_files(&fam, fault_files()).unwrap();
+            repo.backend.inject(Op::Store, 1, fault);
+            assert!(fam.flush().is_err());
+        }
+
+        // A partially written blob is found as an orphan.
+        repo.backend.clear();
+        let orphans = repo.open().reconcile(false).unwrap().orphans.len();
+        assert_eq!(orphans, if fault == Fault::PartialWrite { 1 } else { 0 });
+
+        // Taking the snapshot again works.
+        commit_files(&repo);
+        check_consistent(&repo, Some(fault_files()));
+    }
+}
+
+#[test]
+fn store_same_chunks_after_failed_flush() {
+    let repo = Repo::new("fault-same-chunks");
+    let copies: Vec<_> = fault_files()
+        .into_iter()
+        .zip(vec!["copy1", "copy2", "copy3"])
+        .map(|((_, contents), name)| (name, contents))
+        .collect();
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        repo.backend.inject(Op::Store, 1, Fault::Error);
+        assert!(fam.flush().is_err());
+
+        // The copies reuse the chunks of the failed blob, which the next flush stores.
+        snapshot_files(&fam, copies.clone()).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        check_checkout(&mut hat, fault_files());
+        check_checkout(&mut hat, copies.clone());
+    }
+    check_consistent(&repo, Some(copies));
+}
+
+#[test]
+fn fault_during_commit_is_resumed() {
+    let repo = Repo::new("fault-commit");
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        fam.flush().unwrap();
+        repo.backend.inject(Op::Store, 1, Fault::Error);
+        assert!(hat.commit(&fam, None).is_err());
+    }
+
+    // Reopening finishes the commit.
+    check_consistent(&repo, Some(fault_files()));
+}
+
+#[test]
+fn fault_during_deregister_is_resumed() {
+    let repo = Repo::new("fault-deregister");
+    commit_files(&repo);
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        repo.backend.inject_all(Op::Retrieve, Fault::Error);
+        assert!(hat.deregister(&fam, 1).is_err());
+    }
+
+    // Reopening finishes the delete, after which GC removes all blobs.
+    check_consistent(&repo, None);
+    check_only_snapshot_lists(&repo);
+}
+
+#[test]
+fn fault_during_gc() {
+    let repo = Repo::new("fault-gc");
+    commit_files(&repo);
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        hat.deregister(&fam, 1).unwrap();
+        repo.backend.inject(Op::Delete, 1, Fault::Error);
+        assert!(hat.gc().is_err());
+    }
+
+    check_consistent(&repo, None);
+    check_only_snapshot_lists(&repo);
+}
+
+#[test]
+fn corrupt_data_fails_checkout() {
+    let repo = Repo::new("fault-corrupt");
+    commit_files(&repo);
+    {
+        let mut hat = repo.open();
+        repo.backend.inject_all(Op::Retrieve, Fault::Corrupt);
+        let out = TempDir::new("fault-checkout");
+        assert!(hat.checkout_in_dir(FAMILY.to_owned(), None, out.0.clone()).is_err());
+    }
+
+    check_consistent(&repo, Some(fault_files()));
+}
+
+
+/// Write `fault_files()` the way versions before encryption did: without keys, with chunks
+/// stored as is, and with the snapshot list in a single plaintext root.
+fn plaintext_repo(name: &str) -> Repo {
+    let repo = Repo {
+        dir: TempDir::new(name),
+        backend: Arc::new(FaultyBackend::new(MemoryBackend::new())),
+    };
+    let mut hat = HatRc::open_with_keeper(repo.dir.0.clone(),
+                                          repo.backend.clone(),
+                                          4 * 1024 * 1024,
+                                          crypto::Keeper::plaintext())
+        .unwrap();
+    hat.blob_store.set_codec(blob::Codec::None);
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    snapshot_files(&fam, fault_files()).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+
+    let listing = snapshot_list_to_bytes(&hat.snapshot_index.list_all()[..]).unwrap();
+    for slot in 0..ROOT_GENERATIONS {
+        repo.backend.backend().delete(root_name(slot).as_bytes()).unwrap();
+    }
+    repo.backend.backend().store(LEGACY_ROOT_NAME.as_bytes(), &listing[..]).unwrap();
+    repo
+}
+
+#[test]
+fn open_plaintext_repository() {
+    let repo = plaintext_repo("plaintext");
+    let stored: Vec<Vec<u8>> = repo.backend
+        .backend()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter_map(|n| repo.backend.backend().retrieve(&n[..]).unwrap())
+        .collect();
+    assert!(stored.iter().any(|data| data.windows(1000).any(|w| w == &[3; 1000][..])));
+
+    // Without local state, the snapshots are recovered from the plaintext root.
+    let lost = Repo {
+        dir: TempDir::new("plaintext-lost"),
+        backend: repo.backend.clone(),
+    };
+    {
+        let mut hat = lost.open();
+        hat.recover().unwrap();
+        check_checkout(&mut hat, fault_files());
+    }
+
+    // There is no key to unlock, so no passphrase is asked for.
+    HatRc::open_repository(repo.dir.0.clone(),
+                           repo.backend.clone(),
+                           4 * 1024 * 1024,
+                           || -> Option<String> { panic!("Asked for a passphrase") })
+        .unwrap();
+
+    // The repository can still be backed up, but not be given keys.
+    let mut hat = repo.open();
+    check_checkout(&mut hat, fault_files());
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    snapshot_files(&fam, vec![("name4", vec![4; 1000])]).unwrap();
+    fam.flush().unwrap();
+    hat.commit(&fam, None).unwrap();
+    check_checkout(&mut hat, vec![("name4", vec![4; 1000])]);
+    assert!(HatRc::init_keys(repo.dir.0.clone(), repo.backend.clone(), PASSPHRASE.to_owned())
+        .is_err());
+}
+
+#[test]
+fn missing_keys_are_not_plaintext() {
+    let repo = Repo::new("missing-keys");
+    commit_files(&repo);
+
+    // The local indexes exist, but only a snapshot list from before encryption shows that the
+    // repository is meant to be unencrypted.
+    repo.backend.backend().delete(crypto::KEYRING_NAME.as_bytes()).unwrap();
+    assert!(HatRc::open_repository(repo.dir.0.clone(),
+                                   repo.backend.clone(),
+                                   4 * 1024 * 1024,
+                                   || Some(PASSPHRASE.to_owned()))
+        .is_err());
+}
+
+
+// Crash points: every test below runs an operation that is aborted at each of its flush points
+// in turn, reopens the repository (which resumes the operation) and checks that the result is
+// the same as when nothing crashed.
+
+/// Every hash with its reference count.
+fn refcounts<B: StoreBackend>(hat: &HatRc<B>) -> Vec<(Vec<u8>, i64)> {
+    let mut counts: Vec<_> = hat.hash_index
+        .list()
+        .into_iter()
+        .map(|entry| {
+            let id = hat.hash_index.get_id(&entry.hash).unwrap();
+            (entry.hash.bytes, hat.gc.refcount(id).unwrap())
+        })
+        .collect();
+    counts.sort();
+    counts
+}
+
+#[derive(Debug, PartialEq)]
+struct State {
+    /// Family name, snapshot id, status and top hash of every snapshot.
+    snapshots: Vec<(String, i64, String, Option<Vec<u8>>)>,
+    /// Every hash with its reference count, except for those of the snapshot lists.
+    refcounts: Vec<(Vec<u8>, i64)>,
+    /// The reference counts of the snapshot lists. The lists hold creation times and the names
+    /// of new blobs, so their hashes differ between runs.
+    list_refcounts: Vec<i64>,
+    blobs: usize,
+}
+
+/// The state of a consistent repository after a GC.
+fn state(repo: &Repo) -> State {
+    check_consistent(repo, None);
+    let mut hat = repo.open();
+
+    let mut snapshots: Vec<_> = hat.snapshot_index
+        .list_all()
+        .into_iter()
+        .map(|s| {
+            (s.family_name, s.info.snapshot_id, format!("{:?}", s.status), s.hash.map(|h| h.bytes))
+        })
+        .collect();
+    snapshots.sort();
+
+    let lists = snapshot_lists(&hat).0;
+    let (list_counts, counts): (Vec<_>, Vec<_>) =
+        refcounts(&hat).into_iter().partition(|&(ref hash, _)| lists.contains(hash));
+    let mut list_counts: Vec<i64> = list_counts.into_iter().map(|(_, count)| count).collect();
+    list_counts.sort();
+
+    State {
+        snapshots: snapshots,
+        refcounts: counts,
+        list_refcounts: list_counts,
+        blobs: count_blobs(repo),
+    }
+}
+
+/// Run `op` after `setup`, once without crashing and then crashing at every flush point of `op`
+/// in turn, and check that resuming after the crash gives the same state.
+fn crash_at_every_flush_point<S, F>(name: &str, setup: S, op: F)
+    where S: Fn(&Repo),
+          F: Fn(&mut HatRc<FaultyBackend<MemoryBackend>>) -> Result<(), HatError>
+{
+    // Every run starts from a copy of the same repository, so that hashes can be compared.
+    let template = Repo::new(name);
+    setup(&template);
+
+    let expected = {
+        let repo = Repo::copy_of(name, &template);
+        op(&mut repo.open()).unwrap();
+        state(&repo)
+    };
+
+    let mut point = 1;
+    loop {
+        let repo = Repo::copy_of(name, &template);
+        let crashed = {
+            let mut hat = repo.open();
+            hat.crash_at(Some(point));
+            match op(&mut hat) {
+                Ok(()) => false,
+                Err(HatError::Message(ref msg)) if msg.starts_with(SIMULATED_CRASH) => true,
+                Err(e) => panic!("Unexpected error at flush point {}: {:?}", point, e),
+            }
+        };
+
+        // Reopening resumes the operation.
+        let actual = state(&repo);
+        assert!(actual == expected,
+                "Crash at flush point {} gave {:?}, expected {:?}",
+                point,
+                actual,
+                expected);
+        if !crashed {
+            break;
+        }
+        point += 1;
+    }
+    assert!(point > 1, "{} has no flush points", name);
+}
+
+fn commit_family(hat: &mut HatRc<FaultyBackend<MemoryBackend>>) -> Result<(), HatError> {
+    let fam = try!(hat.open_family(FAMILY.to_owned()));
+    hat.commit(&fam, None)
+}
+
+/// Snapshot `fault_files()` without committing them.
+fn snapshot_fault_files(repo: &Repo) {
+    let hat = repo.open();
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    snapshot_files(&fam, fault_files()).unwrap();
+    fam.flush().unwrap();
+}
+
+#[test]
+fn crash_during_commit() {
+    crash_at_every_flush_point("crash-commit", snapshot_fault_files, commit_family);
+}
+
+#[test]
+fn crash_during_second_commit() {
+    // The second snapshot shares most of its hashes with the first.
+    let setup = |repo: &Repo| {
+        commit_files(repo);
+        let hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, vec![("name4", vec![4; 5000])]).unwrap();
+        fam.flush().unwrap();
+    };
+    crash_at_every_flush_point("crash-recommit", setup, commit_family);
+}
+
+#[test]
+fn crash_during_deregister() {
+    crash_at_every_flush_point("crash-deregister", commit_files, |hat| {
+        let fam = try!(hat.open_family(FAMILY.to_owned()));
+        hat.deregister(&fam, 1)
+    });
+}
+
+#[test]
+fn recover_keys_and_refcounts() {
+    let repo = Repo::new("recover");
+    let expected = {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.commit(&fam, None).unwrap();
+        refcounts(&hat)
+    };
+
+    // Recover from the same backend, without any local state.
+    let lost = Repo {
+        dir: TempDir::new("recover-lost"),
+        backend: repo.backend.clone(),
+    };
+    let mut hat = lost.open();
+    hat.recover().unwrap();
+    assert_eq!(refcounts(&hat), expected);
+
+    // Recounting finds nothing to correct.
+    assert_eq!(hat.rebuild_refcounts().unwrap(), 0);
+
+    // The key index is back, so taking the same snapshot again stores no data.
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    let mut names: Vec<_> =
+        fam.list_from_key_store(None).unwrap().into_iter().map(|(e, _, _)| e.name).collect();
+    names.sort();
+    assert_eq!(names, vec![b"name1".to_vec(), b"name2".to_vec(), b"name3".to_vec()]);
+
+    let objects = lost.backend.list().unwrap().len();
+    snapshot_files(&fam, fault_files()).unwrap();
+    fam.flush().unwrap();
+    assert_eq!(lost.backend.list().unwrap().len(), objects);
+
+    // The recovered repository can be backed up and cleaned up as usual.
+    hat.commit(&fam, None).unwrap();
+    for id in 1..4 {
+        hat.deregister(&fam, id).unwrap();
+    }
+    drop(fam);
+    drop(hat);
+    check_consistent(&lost, None);
+    check_only_snapshot_lists(&lost);
+}
+
+#[test]
+fn meta_commit_keeps_generations() {
+    let repo = Repo::new("meta-commit");
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        fam.flush().unwrap();
+        for _ in 0..ROOT_GENERATIONS + 1 {
+            hat.commit(&fam, None).unwrap();
+        }
+
+        // Only the newest generations are kept.
+        let (roots, _) = hat.read_roots();
+        let generations: Vec<u64> = roots.iter().map(|r| r.generation).collect();
+        assert_eq!(generations, (2..ROOT_GENERATIONS + 2).rev().collect::<Vec<u64>>());
+
+        // Their snapshot lists survive garbage collection.
+        hat.gc().unwrap();
+        for root in roots.iter() {
+            let listed = hat.recover_snapshot_list(root).unwrap();
+            assert_eq!(listed.len() as u64, root.generation);
+        }
+    }
+    check_consistent(&repo, Some(fault_files()));
+}
+
+#[test]
+fn recover_falls_back_to_previous_root() {
+    let repo = Repo::new("recover-fallback");
+    {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.commit(&fam, None).unwrap();
+    }
+
+    // Corrupt the root of the second generation.
+    let name = root_name(2 % ROOT_GENERATIONS);
+    repo.backend.backend().delete(name.as_bytes()).unwrap();
+    repo.backend.backend().store(name.as_bytes(), b"garbage").unwrap();
+
+    let lost = Repo {
+        dir: TempDir::new("recover-fallback-lost"),
+        backend: repo.backend.clone(),
+    };
+    let mut hat = lost.open();
+    hat.recover().unwrap();
+    let ids: Vec<i64> =
+        hat.snapshot_index.list_all().into_iter().map(|s| s.info.snapshot_id).collect();
+    assert_eq!(ids, vec![1]);
+    check_checkout(&mut hat, fault_files());
+
+    // The next generation replaces the corrupt root.
+    let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+    hat.commit(&fam, None).unwrap();
+    let (roots, used) = hat.read_roots();
+    assert_eq!(roots.len(), 2);
+    assert_eq!(used.len(), 2);
+}
+
+#[test]
+fn recover_snapshot_descriptions() {
+    let repo = Repo::new("recover-descriptions");
+    let description = snapshot::Description {
+        msg: Some("before upgrade".to_owned()),
+        host: Some("laptop".to_owned()),
+        path: Some("/home/user".to_owned()),
+    };
+    let created = {
+        let mut hat = repo.open();
+        let fam = hat.open_family(FAMILY.to_owned()).unwrap();
+        snapshot_files(&fam, fault_files()).unwrap();
+        fam.flush().unwrap();
+        hat.commit_described(&fam, &description).unwrap();
+        hat.commit(&fam, None).unwrap();
+        hat.list_snapshots(None).unwrap()[0].created
+    };
+    assert!(created.is_some());
+
+    let lost = Repo {
+        dir: TempDir::new("recover-descriptions-lost"),
+        backend: repo.backend.clone(),
+    };
+    let mut hat = lost.open();
+    hat.recover().unwrap();
+    let listed = hat.list_snapshots(None).unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].created, created);
+    assert_eq!(listed[0].msg, description.msg);
+    assert_eq!(listed[0].host, description.host);
+    assert_eq!(listed[0].path, description.path);
+
+    // Snapshots committed without a description have none.
+    assert!(listed[1].created.is_some());
+    assert_eq!(listed[1].msg, None);
+    assert_eq!(listed[1].host, None);
+}
+
+#[test]
+fn check_finds_no_problems() {
+    let repo = Repo::new("check");
+    commit_files(&repo);
+    commit_files(&repo);
+
+    let mut hat = repo.open();
+    let report = hat.check(false).unwrap();
+    assert_eq!(report.problems, Vec::<Problem>::new());
+    assert_eq!(report.snapshots, 2);
+    assert!(report.refcounts_checked);
+
+    // Reading the data reads more than the directory listings.
+    let listings = report.chunks_read;
+    let report = hat.check(true).unwrap();
+    assert_eq!(report.problems, Vec::<Problem>::new());
+    assert!(report.chunks_read > listings);
+}
+
+#[test]
+fn check_finds_missing_data() {
+    let repo = Repo::new("check-missing");
+    commit_files(&repo);
+
+    let mut hat = repo.open();
+    repo.backend.inject_all(Op::Retrieve, Fault::Missing);
+    let report = hat.check(true).unwrap();
+    assert!(!report.problems.is_empty());
+    // The directory listings are missing too, so the references are unknown.
+    assert!(!report.refcounts_checked);
+    for problem in report.problems.iter() {
+        assert_eq!(problem.snapshot, Some((FAMILY.to_owned(), 1)));
+        assert_eq!(problem.kind, ProblemKind::MissingChunk);
+    }
+}
+
+#[test]
+fn check_finds_wrong_refcount() {
+    let repo = Repo::new("check-refcount");
+    commit_files(&repo);
+
+    let mut hat = repo.open();
+    let entry = hat.hash_index.list().pop().unwrap();
+    let id = hat.hash_index.get_id(&entry.hash).unwrap();
+    let count = hat.gc.refcount(id).unwrap();
+    hat.gc.set_refcount(id, count + 1).unwrap();
+
+    let report = hat.check(false).unwrap();
+    assert_eq!(report.problems,
+               vec![Problem {
+                        snapshot: None,
+                        hash: entry.hash,
+                        kind: ProblemKind::WrongRefcount {
+                            gc: count + 1,
+                            actual: count,
+                        },
+                    }]);
+
+    assert_eq!(hat.rebuild_refcounts().unwrap(), 1);
+    assert_eq!(hat.check(false).unwrap().problems, Vec::<Problem>::new());
+}
+
+/// Every hash in the index, with its level, payload and persistent reference.
+fn hash_entries<B: StoreBackend>(hat: &HatRc<B>)
+                                 -> Vec<(Vec<u8>, i64, Option<Vec<u8>>, Option<blob::ChunkRef>)> {
+    let mut entries: Vec<_> = hat.hash_index
+        .list()
+        .into_iter()
+        .map(|e| (e.hash.bytes, e.level, e.payload, e.persistent_ref))
+        .collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+#[test]
+fn rebuild_index_from_blobs() {
+    let repo = Repo::new("rebuild-index");
+    commit_files(&repo);
+    let mut expected = hash_entries(&repo.open());
+
+    let lost = Repo {
+        dir: TempDir::new("rebuild-index-lost"),
+        backend: repo.backend.clone(),
+    };
+    let mut hat = lost.open();
+    let (blobs, added) = hat.rebuild_index().unwrap();
+    assert_eq!(blobs, count_blobs(&repo));
+
+    // The empty chunk is always added, as it is never stored.
+    let empty = hat.blob_store.keeper().hash(&[]);
+    if expected.iter().all(|e| e.0 != empty.bytes) {
+        let empty_ref = hash_entries(&hat).into_iter().find(|e| e.0 == empty.bytes).unwrap();
+        expected.push(empty_ref);
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    assert_eq!(added, expected.len());
+    assert_eq!(hash_entries(&hat), expected);
+
+    // All blobs are known again, and the snapshots can be recovered.
+    let report = hat.reconcile(false).unwrap();
+    assert_eq!(report.orphans, Vec::<Vec<u8>>::new());
+    assert_eq!(report.missing, Vec::<Vec<u8>>::new());
+    hat.recover().unwrap();
+    check_checkout(&mut hat, fault_files());
+    assert_eq!(hat.check(true).unwrap().problems, Vec::<Problem>::new());
+}
+
+#[test]
+fn gc_waits_for_recover_after_rebuild_index() {
+    let repo = Repo::new("rebuild-index-gc");
+    commit_files(&repo);
+
+    let lost = Repo {
+        dir: TempDir::new("rebuild-index-gc-lost"),
+        backend: repo.backend.clone(),
+    };
+    {
+        let mut hat = lost.open();
+        hat.rebuild_index().unwrap();
+    }
+
+    // Nothing refers to the rebuilt hashes yet, so the GC must not remove them; not even after
+    // opening the repository again.
+    let mut hat = lost.open();
+    assert!(hat.gc().is_err());
+    let blobs = count_blobs(&repo);
+
+    hat.recover().unwrap();
+    hat.gc().unwrap();
+    assert_eq!(count_blobs(&repo), blobs);
+    check_checkout(&mut hat, fault_files());
+    assert_eq!(hat.check(true).unwrap().problems, Vec::<Problem>::new());
 }
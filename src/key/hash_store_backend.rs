@@ -16,6 +16,7 @@ use std::sync::Arc;
 
 use backend::StoreBackend;
 use blob;
+use crypto;
 use errors::RetryError;
 use hash;
 use key::MsgError;
@@ -24,12 +25,14 @@ use hash::tree::HashTreeBackend;
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    keeper: Arc<crypto::Keeper>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
         HashStoreBackend {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
+            keeper: self.keeper.clone(),
         }
     }
 }
@@ -38,9 +41,11 @@ impl<B: StoreBackend> HashStoreBackend<B> {
     pub fn new(hash_index: Arc<hash::HashIndex>,
                blob_store: Arc<blob::BlobStore<B>>)
                -> HashStoreBackend<B> {
+        let keeper = blob_store.keeper();
         HashStoreBackend {
             hash_index: hash_index,
             blob_store: blob_store,
+            keeper: keeper,
         }
     }
 
@@ -76,7 +81,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         };
 
         Ok(data_opt.and_then(|data| {
-            let actual_hash = hash::Hash::new(&data[..]);
+            let actual_hash = self.hash(&data[..]);
             if *hash == actual_hash {
                 Some(data)
             } else {
@@ -88,6 +93,10 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         }))
     }
 
+    fn hash(&self, data: &[u8]) -> hash::Hash {
+        self.keeper.hash(data)
+    }
+
     fn fetch_persistent_ref(&self, hash: &hash::Hash) -> Option<blob::ChunkRef> {
         assert!(!hash.bytes.is_empty());
         loop {
@@ -124,8 +133,10 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         match self.hash_index.reserve(&hash_entry) {
             hash::ReserveResult::HashKnown(..) => {
                 // Someone came before us: piggyback on their result.
-                Ok(self.fetch_persistent_ref(hash)
-                    .expect("Could not find persistent_ref for known chunk."))
+                match self.fetch_persistent_ref(hash) {
+                    Some(chunk_ref) => Ok(chunk_ref),
+                    None => Err(From::from("Known chunk could not be stored")),
+                }
             }
             hash::ReserveResult::ReserveOk(..) => {
                 // We came first: this data-chunk is ours to process.
@@ -140,7 +151,14 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 } else {
                     blob::Kind::TreeBranch
                 };
-                let chunk_ref = self.blob_store.store(chunk, kind, callback);
+                let chunk_ref = match self.blob_store.store(chunk, kind, callback) {
+                    Ok(chunk_ref) => chunk_ref,
+                    Err(e) => {
+                        // The chunk was not taken, so nothing can refer to it.
+                        self.hash_index.unreserve(hash);
+                        return Err(From::from(e));
+                    }
+                };
                 hash_entry.persistent_ref = Some(chunk_ref.clone());
                 self.hash_index.update_reserved(hash_entry);
                 Ok(chunk_ref)
@@ -107,7 +107,8 @@ impl InternalKeyIndex {
                           name.eq(&entry.name[..]),
                           created.eq(entry.created),
                           modified.eq(entry.modified),
-                          accessed.eq(entry.accessed)))
+                          accessed.eq(entry.accessed),
+                          data_length.eq(entry.data_length.map(|x| x as i64))))
                     .execute(&self.conn));
                 entry
             }
@@ -125,6 +126,7 @@ impl InternalKeyIndex {
                         user_id: entry.user_id.map(|x| x as i64),
                         hash: None,
                         persistent_ref: None,
+                        data_length: entry.data_length.map(|x| x as i64),
                     };
 
                     try!(diesel::insert(&new)
@@ -175,7 +177,7 @@ impl InternalKeyIndex {
                 user_id: row.user_id.map(|x| x as u64),
                 group_id: row.group_id.map(|x| x as u64),
                 data_hash: row.hash,
-                data_length: None,
+                data_length: row.data_length.map(|x| x as u64),
             }))
         } else {
             Ok(None)
@@ -246,7 +248,7 @@ impl InternalKeyIndex {
                     user_id: r.user_id.map(|x| x as u64),
                     group_id: r.group_id.map(|x| x as u64),
                     data_hash: r.hash,
-                    data_length: None,
+                    data_length: r.data_length.map(|x| x as u64),
                 },
                  r.persistent_ref
                     .as_mut()
@@ -14,11 +14,13 @@
 
 //! External API for creating and manipulating snapshots.
 
+use std::io;
 use std::sync::Arc;
 use std::borrow::Cow;
 
 use backend::StoreBackend;
 use blob;
+use crypto;
 use hash;
 use hash::tree::{ReaderResult, SimpleHashTreeReader, SimpleHashTreeWriter};
 
@@ -129,7 +131,8 @@ impl<B: StoreBackend> Store<B> {
         let ki_p = Arc::new(try!(index::KeyIndex::new_for_testing()));
         let hi_p = Arc::new(try!(hash::HashIndex::new_for_testing()));
         let blob_index = Arc::new(try!(blob::BlobIndex::new_for_testing()));
-        let bs_p = Arc::new(blob::BlobStore::new(blob_index, backend, max_blob_size));
+        let keeper = Arc::new(crypto::Keeper::new_for_testing());
+        let bs_p = Arc::new(blob::BlobStore::new(blob_index, backend, keeper, max_blob_size));
         Ok(Store {
             index: ki_p,
             hash_index: hi_p,
@@ -138,13 +141,35 @@ impl<B: StoreBackend> Store<B> {
     }
 
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        try!(self.blob_store.flush());
         self.hash_index.flush();
         try!(self.index.flush());
 
         Ok(())
     }
 
+    /// Insert an entry recovered from a committed snapshot, along with the data hash it had.
+    /// An existing entry with the same parent and name is replaced. Returns the entry ID.
+    pub fn recover(&self,
+                   entry: Entry,
+                   data: Option<(hash::Hash, blob::ChunkRef)>)
+                   -> Result<u64, MsgError> {
+        let entry = match try!(self.index.lookup(entry.parent_id, entry.name.clone())) {
+            Some(existing) => Entry { id: existing.id, ..entry },
+            None => Entry { id: None, ..entry },
+        };
+        let entry = try!(self.index.insert(entry));
+        let id = entry.id.unwrap();
+
+        let (hash, persistent_ref) = match data {
+            Some((hash, persistent_ref)) => (Some(hash), Some(persistent_ref)),
+            None => (None, None),
+        };
+        try!(self.index.update_data_hash(id, None, hash, persistent_ref));
+
+        Ok(id)
+    }
+
     pub fn hash_tree_writer(&mut self) -> SimpleHashTreeWriter<HashStoreBackend<B>> {
         let backend = HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone());
         SimpleHashTreeWriter::new(8, backend)
@@ -165,7 +190,10 @@ fn file_size_warning(name: &[u8], wanted: u64, got: u64) {
     }
 }
 
-impl<IT: Iterator<Item = Vec<u8>>, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
+impl<IT, B> MsgHandler<Msg<IT>, Reply<B>> for Store<B>
+    where IT: Iterator<Item = io::Result<Vec<u8>>>,
+          B: StoreBackend
+{
     type Err = MsgError;
 
     fn handle<F: FnOnce(Result<Reply<B>, MsgError>)>(&mut self,
@@ -262,6 +290,19 @@ impl<IT: Iterator<Item = Vec<u8>>, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>
                 // (see HashStoreBackend::insert_chunk above)
                 let mut bytes_read = 0u64;
                 for chunk in it_opt.unwrap() {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            // Like a file that can not be opened, the entry gets no data. It is
+                            // read again by the next snapshot.
+                            println!("Skipping {:?}: {}", entry.name, e);
+                            try!(self.index.update_data_hash(entry.id.unwrap(),
+                                                             entry.modified,
+                                                             None,
+                                                             None));
+                            return Ok(());
+                        }
+                    };
                     bytes_read += chunk.len() as u64;
                     try!(tree.append(chunk));
                 }
@@ -30,6 +30,8 @@ table! {
 
         hash -> Nullable<Binary>,
         persistent_ref -> Nullable<Binary>,
+
+        data_length -> Nullable<BigInt>,
     }
 }
 
@@ -52,6 +54,8 @@ pub struct Key {
 
     pub hash: Option<Vec<u8>>,
     pub persistent_ref: Option<Vec<u8>>,
+
+    pub data_length: Option<i64>,
 }
 
 #[insertable_into(keys)]
@@ -69,4 +73,6 @@ pub struct NewKey<'a> {
 
     pub hash: Option<&'a [u8]>,
     pub persistent_ref: Option<&'a [u8]>,
+
+    pub data_length: Option<i64>,
 }
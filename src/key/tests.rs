@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use key::*;
+use std::io;
 use std::sync::Arc;
 
 use backend::{MemoryBackend, StoreBackend};
@@ -35,13 +36,13 @@ pub struct EntryStub {
 }
 
 impl Iterator for EntryStub {
-    type Item = Vec<u8>;
+    type Item = io::Result<Vec<u8>>;
 
-    fn next(&mut self) -> Option<Vec<u8>> {
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
         match self.data.as_mut() {
             Some(x) => {
                 if !x.is_empty() {
-                    Some(x.remove(0))
+                    Some(Ok(x.remove(0)))
                 } else {
                     None
                 }
@@ -27,11 +27,13 @@ extern crate time;
 
 // Rust crates.
 extern crate capnp;
+extern crate hyper;
 extern crate sodiumoxide;
 extern crate libsodium_sys;
 extern crate rustc_serialize;
 extern crate scoped_threadpool;
 extern crate void;
+extern crate zstd;
 
 // Error definition macros.
 #[macro_use]
@@ -48,6 +50,7 @@ extern crate quickcheck;
 // Submodules
 pub mod backend;
 mod blob;
+mod crypto;
 mod errors;
 mod gc;
 mod hash;
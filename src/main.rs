@@ -17,18 +17,29 @@ extern crate hat;
 
 // Rust crates.
 extern crate env_logger;
+extern crate libc;
+extern crate rustc_serialize;
 extern crate sodiumoxide;
+extern crate time;
 
 // We use Clap for argument parsing.
 #[macro_use]
 extern crate clap;
 
 use std::borrow::ToOwned;
+use std::collections::BTreeMap;
 use std::convert::From;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
 use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
 
-use clap::{App, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{Json, ToJson};
 
 use hat::backend;
 
@@ -38,6 +49,166 @@ fn blob_dir() -> PathBuf {
     PathBuf::from("blobs")
 }
 
+fn repository_root() -> PathBuf {
+    PathBuf::from("repo")
+}
+
+/// Ask for a passphrase. When reading from a terminal, what is typed is not echoed.
+fn prompt(msg: &str) -> String {
+    print!("{}", msg);
+    io::stdout().flush().unwrap();
+
+    let mut term: libc::termios = unsafe { mem::zeroed() };
+    let is_terminal = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut term) } == 0;
+    if is_terminal {
+        let mut quiet = term;
+        quiet.c_lflag &= !libc::ECHO;
+        quiet.c_lflag |= libc::ECHONL;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) };
+    }
+
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+    if is_terminal {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) };
+    }
+    read.unwrap();
+    line.trim_right_matches(|c: char| c == '\n' || c == '\r').to_owned()
+}
+
+/// Passphrase for unlocking the repository: $HAT_PASSPHRASE, or ask the user.
+fn passphrase() -> String {
+    match env::var("HAT_PASSPHRASE") {
+        Ok(p) => p,
+        Err(_) => prompt("Passphrase: "),
+    }
+}
+
+/// Passphrase for a new key: $HAT_NEW_PASSPHRASE, or ask the user (twice).
+fn new_passphrase() -> String {
+    if let Ok(p) = env::var("HAT_NEW_PASSPHRASE") {
+        return p;
+    }
+    loop {
+        let first = prompt("New passphrase: ");
+        if first.is_empty() {
+            println!("The passphrase must not be empty.");
+        } else if first != prompt("Repeat new passphrase: ") {
+            println!("The passphrases do not match.");
+        } else {
+            return first;
+        }
+    }
+}
+
+/// S3 credentials from the environment, as used by the AWS tools.
+fn s3_credentials() -> (String, String) {
+    match (env::var("AWS_ACCESS_KEY_ID"), env::var("AWS_SECRET_ACCESS_KEY")) {
+        (Ok(access_key), Ok(secret_key)) => (access_key, secret_key),
+        _ => {
+            println!("S3 needs $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The backend selected on the command line: S3 or external commands if given, otherwise files
+/// in the blob directory.
+fn open_storage(matches: &ArgMatches) -> Box<backend::StoreBackend> {
+    if let Some(endpoint) = matches.value_of("s3-endpoint") {
+        let bucket = match matches.value_of("s3-bucket") {
+            Some(bucket) => bucket.to_owned(),
+            None => {
+                println!("--s3-endpoint needs --s3-bucket");
+                std::process::exit(1);
+            }
+        };
+        let (access_key, secret_key) = s3_credentials();
+        let mut config =
+            backend::S3Config::new(endpoint.to_owned(), bucket, access_key, secret_key);
+        if let Some(region) = matches.value_of("s3-region") {
+            config.region = region.to_owned();
+        }
+        if let Some(prefix) = matches.value_of("s3-prefix") {
+            config.prefix = prefix.to_owned();
+        }
+        return Box::new(backend::S3Backend::new(config));
+    }
+
+    let commands = (matches.value_of("put-cmd"),
+                    matches.value_of("get-cmd"),
+                    matches.value_of("del-cmd"));
+    let backend: Box<backend::StoreBackend> = match commands {
+        (Some(store), Some(retrieve), Some(delete)) => {
+            Box::new(backend::CommandBackend::new(backend::Commands {
+                store: store.to_owned(),
+                retrieve: retrieve.to_owned(),
+                delete: delete.to_owned(),
+                flush: matches.value_of("flush-cmd").map(|s| s.to_owned()),
+                list: matches.value_of("list-cmd").map(|s| s.to_owned()),
+            }))
+        }
+        (None, None, None) => {
+            let cache_size = match matches.value_of("cache-size") {
+                Some(mb) => mb.parse::<usize>().expect("Invalid cache size") * 1024 * 1024,
+                None => backend::DEFAULT_CACHE_SIZE,
+            };
+            Box::new(backend::FileBackend::with_cache_size(blob_dir(), cache_size))
+        }
+        _ => {
+            println!("--put-cmd, --get-cmd and --del-cmd must be given together");
+            std::process::exit(1);
+        }
+    };
+    backend
+}
+
+fn retrying(storage: Box<backend::StoreBackend>,
+            matches: &ArgMatches)
+            -> Box<backend::StoreBackend> {
+    let attempts = match matches.value_of("retries") {
+        Some(n) => 1 + n.parse::<u32>().expect("Invalid number of retries"),
+        None => backend::DEFAULT_ATTEMPTS,
+    };
+    Box::new(backend::RetryingBackend::with_attempts(storage, attempts, backend::DEFAULT_DELAY_MS))
+}
+
+/// The selected backend mirrored to the `--mirror` directories, if any are given.
+fn open_mirror(matches: &ArgMatches)
+               -> Option<backend::MirrorBackend<Box<backend::StoreBackend>>> {
+    let dirs = match matches.values_of("mirror") {
+        Some(dirs) => dirs,
+        None => return None,
+    };
+    let mut replicas = vec![retrying(open_storage(matches), matches)];
+    for dir in dirs {
+        replicas.push(retrying(Box::new(backend::FileBackend::new(PathBuf::from(dir))), matches));
+    }
+    let quorum = match matches.value_of("mirror-quorum") {
+        Some(n) => n.parse::<usize>().expect("Invalid quorum"),
+        None => replicas.len(),
+    };
+    let mut journal = repository_root();
+    journal.push("mirror-journal");
+    Some(backend::MirrorBackend::new(replicas, quorum, Some(journal)).unwrap())
+}
+
+/// The selected backend, with failed operations retried a few times.
+fn open_backend(matches: &ArgMatches) -> Arc<Box<backend::StoreBackend>> {
+    match open_mirror(matches) {
+        Some(mirror) => Arc::new(Box::new(mirror)),
+        None => Arc::new(retrying(open_storage(matches), matches)),
+    }
+}
+
+fn open_hat(matches: &ArgMatches) -> hat::hat::HatRc<Box<backend::StoreBackend>> {
+    hat::Hat::open_repository(repository_root(),
+                              open_backend(matches),
+                              MAX_BLOB_SIZE,
+                              || Some(passphrase()))
+        .unwrap()
+}
+
 fn license() {
     println!(include_str!("../LICENSE"));
     println!("clap (Command Line Argument Parser) License:");
@@ -45,6 +216,81 @@ fn license() {
 }
 
 
+/// The name of this host, to record in new snapshots.
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let name = if res == 0 {
+        // The name is not terminated if it was truncated.
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        String::from_utf8(buf[..len].to_vec()).ok()
+    } else {
+        None
+    };
+    name.or_else(|| env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .and_then(|name| if name.is_empty() { None } else { Some(name) })
+}
+
+/// Parse a time given on the command line as seconds since the epoch.
+fn parse_time(text: &str) -> Option<i64> {
+    if let Ok(secs) = text.parse::<i64>() {
+        return Some(secs);
+    }
+    ["%Y-%m-%dT%H:%M:%SZ", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d"]
+        .iter()
+        .filter_map(|format| time::strptime(text, format).ok())
+        .map(|tm| tm.to_timespec().sec)
+        .next()
+}
+
+/// Seconds since the epoch as an RFC 3339 timestamp.
+fn format_time(secs: i64) -> String {
+    time::at_utc(time::Timespec::new(secs, 0)).rfc3339().to_string()
+}
+
+fn snapshot_json(snapshot: &hat::hat::SnapshotListing) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("family".to_owned(), snapshot.family_name.to_json());
+    object.insert("id".to_owned(), snapshot.id.to_json());
+    object.insert("status".to_owned(), snapshot.status.to_json());
+    object.insert("created".to_owned(),
+                  snapshot.created.map(format_time).to_json());
+    object.insert("message".to_owned(), snapshot.msg.to_json());
+    object.insert("host".to_owned(), snapshot.host.to_json());
+    object.insert("path".to_owned(), snapshot.path.to_json());
+    object.insert("size".to_owned(), snapshot.size.to_json());
+    object.insert("unique_size".to_owned(), snapshot.unique_size.to_json());
+    Json::Object(object)
+}
+
+fn print_snapshots(snapshots: &[hat::hat::SnapshotListing]) {
+    fn or_dash<T: ToString>(value: Option<T>) -> String {
+        value.map_or("-".to_owned(), |v| v.to_string())
+    }
+
+    println!("FAMILY\tID\tSTATUS\tCREATED\tSOURCE\tSIZE\tUNIQUE\tMESSAGE");
+    for s in snapshots.iter() {
+        let source = match (&s.host, &s.path) {
+            (&None, &None) => None,
+            (host, path) => {
+                Some(format!("{}:{}",
+                             host.as_ref().map_or("", |h| &h[..]),
+                             path.as_ref().map_or("", |p| &p[..])))
+            }
+        };
+        println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 s.family_name,
+                 s.id,
+                 s.status,
+                 or_dash(s.created.map(format_time)),
+                 or_dash(source),
+                 or_dash(s.size),
+                 or_dash(s.unique_size),
+                 s.msg.as_ref().map_or("", |m| &m[..]));
+    }
+}
+
 fn main() {
     env_logger::init().unwrap();
 
@@ -59,17 +305,50 @@ fn main() {
         .version(&format!("v{}", crate_version!())[..])
         .about("Create backup snapshots")
         .arg_from_usage("--license 'Display the license'")
+        .args_from_usage("--put-cmd [CMD] 'Store blobs with this shell command (name in $1, data \
+                                           on stdin)'
+                          --get-cmd [CMD] 'Retrieve blobs with this shell command (data on \
+                                           stdout, exit status 4 if missing)'
+                          --del-cmd [CMD] 'Delete blobs with this shell command'
+                          --flush-cmd [CMD] 'Flush stored blobs with this shell command'
+                          --list-cmd [CMD] 'List blob names with this shell command'
+                          --s3-endpoint [URL] 'Store blobs in S3 compatible storage at this URL'
+                          --s3-bucket [BUCKET] 'S3 bucket to store blobs in'
+                          --s3-prefix [PREFIX] 'Prefix for S3 object keys'
+                          --s3-region [REGION] 'S3 region (default: us-east-1)'
+                          --cache-size [MB] 'Size of the blob read cache in MiB (default: 64)'
+                          --retries [N] 'Retry failed storage operations N times (default: 4)'
+                          --mirror [DIR]... 'Also store blobs in this directory'
+                          --mirror-quorum [N] 'Number of copies a store must reach (default: \
+                                               all)'")
         .subcommand(SubCommand::with_name("snapshot")
             .about("Create a snapshot")
             .args_from_usage(arg_template))
         .subcommand(SubCommand::with_name("checkout")
             .about("Checkout a snapshot")
-            .args_from_usage(arg_template))
+            .args_from_usage(arg_template)
+            .arg(Arg::from_usage("--id [ID] 'Check out this snapshot instead of the latest'")
+                .conflicts_with("before"))
+            .arg_from_usage("--before [TIME] 'Check out the latest snapshot taken before this \
+                             time (YYYY-MM-DD, YYYY-MM-DDTHH:MM:SSZ or seconds since the epoch, \
+                             in UTC)'")
+            .args_from_usage("--path [SUBPATH] 'Only check out this file or directory of the \
+                              snapshot'
+                              --include [GLOB]... 'Only check out the files matching this \
+                              pattern'
+                              --exclude [GLOB]... 'Skip the files and directories matching this \
+                              pattern'"))
         .subcommand(SubCommand::with_name("commit")
             .about("Commit a snapshot")
-            .arg_from_usage("<NAME> 'Name of the snapshot'"))
+            .args_from_usage("<NAME> 'Name of the snapshot'
+                              -m --message [MSG] 'Describe the snapshot'
+                              --path [PATH] 'The path the snapshot was taken from'"))
+        .subcommand(SubCommand::with_name("list")
+            .about("List snapshots")
+            .args_from_usage("[FAMILY] 'Only list the snapshots of this family'
+                              --json 'Print the list as JSON'"))
         .subcommand(SubCommand::with_name("meta-commit")
-            .about("Commit snapshot metadata (required for recover command"))
+            .about("Commit snapshot metadata (done after every commit and delete)"))
         .subcommand(SubCommand::with_name("recover").about("Recover list of commit'ed snapshots"))
         .subcommand(SubCommand::with_name("delete")
             .about("Delete a snapshot")
@@ -79,7 +358,30 @@ fn main() {
         .subcommand(SubCommand::with_name("gc")
             .about("Garbage collect: identify and remove unused data blocks.")
             .args_from_usage("-p --pretend 'Do not modify any data'"))
+        .subcommand(SubCommand::with_name("reconcile")
+            .about("Find blobs missing from the storage, or left there unused")
+            .args_from_usage("--delete 'Delete the unused blobs'"))
+        .subcommand(SubCommand::with_name("rebuild-index")
+            .about("Rebuild the local blob and hash indexes from the stored blobs"))
+        .subcommand(SubCommand::with_name("check")
+            .about("Check that all snapshots can be read back")
+            .args_from_usage("--read-data 'Also download and verify all data'"))
+        .subcommand(SubCommand::with_name("mirror-repair")
+            .about("Copy blobs to the mirrors that are missing them")
+            .args_from_usage("--full 'Compare the contents of all mirrors'"))
         .subcommand(SubCommand::with_name("resume").about("Resume previous failed command."))
+        .subcommand(SubCommand::with_name("compression")
+            .about("Show or change the compression used for new data")
+            .arg_from_usage("[CODEC] 'none or zstd'"))
+        .subcommand(SubCommand::with_name("key")
+            .about("Manage the keys that unlock the repository")
+            .subcommand(SubCommand::with_name("init").about("Create the first key"))
+            .subcommand(SubCommand::with_name("add").about("Add a key with a new passphrase"))
+            .subcommand(SubCommand::with_name("remove")
+                .about("Remove a key")
+                .arg_from_usage("<ID> 'The id of the key to remove'"))
+            .subcommand(SubCommand::with_name("change-passphrase")
+                .about("Change the passphrase of a key")))
         .get_matches();
 
     // Check for license flag
@@ -94,16 +396,13 @@ fn main() {
     match matches.subcommand() {
         ("resume", Some(_cmd)) => {
             // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE).unwrap();
+            open_hat(&matches);
         }
         ("snapshot", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let hat = open_hat(&matches);
 
             let family = hat.open_family(name.clone())
                 .expect(&format!("Could not open family '{}'", name));
@@ -116,55 +415,185 @@ fn main() {
         ("checkout", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
+            let id = cmd.value_of("id").map(|id| {
+                id.parse::<i64>().expect(&format!("Invalid snapshot id '{}'", id))
+            });
+            let before = cmd.value_of("before").map(|t| {
+                parse_time(t).expect(&format!("Invalid time '{}'", t))
+            });
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let globs = |arg: &str| {
+                cmd.values_of(arg)
+                    .map_or(vec![], |values| values.map(hat::hat::Glob::new).collect())
+            };
+            let filter = hat::hat::CheckoutFilter {
+                path: cmd.value_of("path").map(PathBuf::from),
+                include: globs("include"),
+                exclude: globs("exclude"),
+            };
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            let mut hat = open_hat(&matches);
+
+            let result = match before {
+                Some(before) => hat.latest_complete(&name, Some(before)).map(Some),
+                None => Ok(id),
+            }
+            .and_then(|id| hat.checkout_filtered_in_dir(name, id, PathBuf::from(path), &filter));
+            if let Err(e) = result {
+                println!("Checkout failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        ("list", Some(cmd)) => {
+            let mut hat = open_hat(&matches);
+            let snapshots = hat.list_snapshots(cmd.value_of("FAMILY")).unwrap();
+            if cmd.is_present("json") {
+                let list: Vec<Json> = snapshots.iter().map(snapshot_json).collect();
+                println!("{}", Json::Array(list).pretty());
+            } else {
+                print_snapshots(&snapshots[..]);
+            }
         }
         ("meta-commit", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = open_hat(&matches);
 
             hat.meta_commit().unwrap();
         }
         ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = open_hat(&matches);
 
-            hat.recover().unwrap();
+            let corrected = hat.recover().unwrap();
+            if corrected > 0 {
+                println!("Corrected {} reference counts", corrected);
+            }
         }
         ("commit", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
+            let description = hat::hat::SnapshotDescription {
+                msg: cmd.value_of("message").map(|m| m.to_owned()),
+                host: hostname(),
+                path: cmd.value_of("path").map(|p| {
+                    fs::canonicalize(p)
+                        .map(|p| p.to_string_lossy().into_owned())
+                        .unwrap_or(p.to_owned())
+                }),
+            };
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = open_hat(&matches);
 
-            hat.commit_by_name(name, None).unwrap();
+            let family = hat.open_family(name.clone())
+                .expect(&format!("Could not open family '{}'", name));
+            hat.commit_described(&family, &description).unwrap();
         }
         ("delete", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let id = cmd.value_of("ID").unwrap().to_owned();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = open_hat(&matches);
 
             hat.deregister_by_name(name, id.parse::<i64>().unwrap()).unwrap();
         }
         ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = open_hat(&matches);
             let (deleted_hashes, live_blobs) = hat.gc().unwrap();
             println!("Deleted hashes: {:?}", deleted_hashes);
             println!("Live data blobs after deletion: {:?}", live_blobs);
 
         }
+        ("reconcile", Some(cmd)) => {
+            let mut hat = open_hat(&matches);
+            let delete = cmd.is_present("delete");
+            let result = hat.reconcile(delete).unwrap();
+            for name in result.orphans.iter() {
+                println!("{} {}", if delete { "deleted" } else { "orphan" }, name.to_hex());
+            }
+            for name in result.missing.iter() {
+                println!("missing {}", name.to_hex());
+            }
+            for name in result.unknown.iter() {
+                println!("unknown {}", name.to_hex());
+            }
+            println!("Orphaned blobs: {}", result.orphans.len());
+            println!("Missing blobs: {}", result.missing.len());
+            println!("Unknown objects: {}", result.unknown.len());
+        }
+        ("rebuild-index", Some(_cmd)) => {
+            let mut hat = open_hat(&matches);
+            let (blobs, hashes) = hat.rebuild_index().unwrap();
+            println!("Blobs read: {}", blobs);
+            println!("Hashes added: {}", hashes);
+            println!("Run 'recover' to restore the snapshots; gc is disabled until then");
+        }
+        ("check", Some(cmd)) => {
+            let mut hat = open_hat(&matches);
+            let report = hat.check(cmd.is_present("read-data")).unwrap();
+            for problem in report.problems.iter() {
+                println!("{}", problem);
+            }
+            println!("Snapshots checked: {}", report.snapshots);
+            println!("Hashes checked: {}", report.hashes);
+            println!("Chunks read: {}", report.chunks_read);
+            if !report.refcounts_checked {
+                println!("Reference counts not checked, as some directories could not be read");
+            }
+            println!("Problems found: {}", report.problems.len());
+            if !report.problems.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        ("mirror-repair", Some(cmd)) => {
+            let mirror = match open_mirror(&matches) {
+                Some(mirror) => mirror,
+                None => {
+                    println!("mirror-repair needs at least one --mirror");
+                    std::process::exit(1);
+                }
+            };
+            let (repaired, remaining) = mirror.repair(cmd.is_present("full")).unwrap();
+            println!("Repaired blobs: {}", repaired);
+            println!("Repairs still pending: {}", remaining);
+        }
+        ("compression", Some(cmd)) => {
+            let mut hat = open_hat(&matches);
+            match cmd.value_of("CODEC") {
+                Some(name) => {
+                    let codec = hat::hat::Codec::from_name(name)
+                        .expect(&format!("Unknown compression '{}'", name));
+                    hat.set_compression(codec).unwrap();
+                }
+                None => println!("{}", hat.compression().unwrap().name()),
+            }
+        }
+        ("key", Some(cmd)) => {
+            let backend = open_backend(&matches);
+            match cmd.subcommand() {
+                ("init", Some(_cmd)) => {
+                    let id = hat::Hat::init_keys(repository_root(), backend, new_passphrase())
+                        .unwrap();
+                    println!("Created key #{}", id);
+                }
+                ("add", Some(_cmd)) => {
+                    let id = hat::Hat::add_key(backend, passphrase(), new_passphrase()).unwrap();
+                    println!("Added key #{}", id);
+                }
+                ("remove", Some(cmd)) => {
+                    let id = cmd.value_of("ID").unwrap().parse::<u64>().unwrap();
+                    hat::Hat::remove_key(backend, passphrase(), id).unwrap();
+                    println!("Removed key #{}", id);
+                }
+                ("change-passphrase", Some(_cmd)) => {
+                    let id = hat::Hat::change_passphrase(backend, passphrase(), new_passphrase())
+                        .unwrap();
+                    println!("Changed passphrase of key #{}", id);
+                }
+                _ => {
+                    println!("No key subcommand specified\n{}\nFor more information re-run \
+                              with --help",
+                             cmd.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
         _ => {
             println!("No subcommand specified\n{}\nFor more information re-run with --help",
                      matches.usage());
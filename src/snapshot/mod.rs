@@ -17,6 +17,7 @@
 use diesel;
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
+use time;
 
 use blob;
 use errors::DieselError;
@@ -34,6 +35,15 @@ pub struct Info {
     pub snapshot_id: i64,
 }
 
+/// What a new snapshot is given when it is reserved.
+#[derive(Clone, Debug, Default)]
+pub struct Description {
+    pub msg: Option<String>,
+    /// The host and path the snapshot was taken from.
+    pub host: Option<String>,
+    pub path: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum WorkStatus {
     CommitInProgress,
@@ -51,6 +61,12 @@ pub struct Status {
     pub msg: Option<String>,
     pub tree_ref: Option<Vec<u8>>,
     pub status: WorkStatus,
+    /// When the snapshot was taken, in seconds since the epoch.
+    pub created: Option<i64>,
+    /// The total size of the files in the snapshot.
+    pub size: Option<u64>,
+    pub host: Option<String>,
+    pub path: Option<String>,
 }
 
 
@@ -79,6 +95,34 @@ fn work_status_to_tag(status: WorkStatus) -> tags::Tag {
     }
 }
 
+fn to_status(snap: self::schema::Snapshot, family_name: String) -> Status {
+    let status = tags::tag_from_num(snap.tag as i64)
+        .map_or(WorkStatus::CommitComplete, tag_to_work_status);
+    let hash = snap.hash.and_then(|bytes| {
+        if bytes.is_empty() {
+            None
+        } else {
+            Some(::hash::Hash { bytes: bytes })
+        }
+    });
+    Status {
+        family_name: family_name,
+        msg: snap.msg,
+        hash: hash,
+        tree_ref: snap.tree_ref,
+        status: status,
+        created: snap.created,
+        size: snap.size.map(|s| s as u64),
+        host: snap.host,
+        path: snap.path,
+        info: Info {
+            unique_id: snap.id,
+            snapshot_id: snap.snapshot_id,
+            family_id: snap.family_id,
+        },
+    }
+}
+
 impl SnapshotIndex {
     pub fn new(path: &str) -> Result<SnapshotIndex, DieselError> {
         let conn = try!(SqliteConnection::establish(path));
@@ -158,33 +202,23 @@ impl SnapshotIndex {
     }
 
     /// Lookup exact snapshot info from family and snapshot id.
-    pub fn lookup(&mut self,
-                  family_name_: &str,
-                  snapshot_id_: i64)
-                  -> Option<(Info, hash::Hash, Option<blob::ChunkRef>)> {
+    pub fn lookup(&mut self, family_name_: &str, snapshot_id_: i64) -> Option<Status> {
         use self::schema::snapshots::dsl::*;
         use self::schema::family::dsl::{family, name};
 
         let row_opt = snapshots.inner_join(family)
             .filter(name.eq(family_name_))
             .filter(snapshot_id.eq(snapshot_id_))
-            .select((id, tag, family_id, snapshot_id, msg, hash, tree_ref))
+            .select((id, tag, family_id, snapshot_id, msg, hash, tree_ref, created, size, host,
+                     path))
             .first::<self::schema::Snapshot>(&self.conn)
             .optional()
             .expect("Error reading snapshot info");
 
-        row_opt.map(|snap| {
-            (Info {
-                unique_id: snap.id,
-                family_id: snap.family_id,
-                snapshot_id: snap.snapshot_id,
-            },
-             ::hash::Hash { bytes: snap.hash.unwrap().to_vec() },
-             ::blob::ChunkRef::from_bytes(&mut &snap.tree_ref.unwrap()[..]).ok())
-        })
+        row_opt.map(|snap| to_status(snap, family_name_.to_owned()))
     }
 
-    pub fn reserve(&mut self, family_: String) -> Info {
+    pub fn reserve(&mut self, family_: String, description: &Description) -> Info {
         use self::schema::snapshots::dsl::*;
 
         let family_id_ = self.get_or_create_family_id(&family_);
@@ -194,9 +228,13 @@ impl SnapshotIndex {
             family_id: family_id_,
             snapshot_id: snapshot_id_,
             tag: tags::Tag::Reserved as i32,
-            msg: None,
+            msg: description.msg.as_ref().map(|m| &m[..]),
             hash: None,
             tree_ref: None,
+            created: Some(time::get_time().sec),
+            size: None,
+            host: description.host.as_ref().map(|h| &h[..]),
+            path: description.path.as_ref().map(|p| &p[..]),
         };
 
         diesel::insert(&new)
@@ -213,26 +251,22 @@ impl SnapshotIndex {
         }
     }
 
-    fn update_internal(&mut self,
-                       snapshot_: &Info,
-                       msg_: &str,
-                       hash_: &hash::Hash,
-                       tree_ref_: &blob::ChunkRef) {
+    /// Update existing snapshot. `size_` is the total size of its files.
+    pub fn update(&mut self,
+                  snapshot_: &Info,
+                  hash_: &hash::Hash,
+                  tree_ref_: &blob::ChunkRef,
+                  size_: u64) {
         use self::schema::snapshots::dsl::*;
 
         diesel::update(snapshots.find(snapshot_.unique_id))
-            .set((msg.eq(Some(msg_)),
-                  hash.eq(Some(&hash_.bytes)),
-                  tree_ref.eq(Some(tree_ref_.as_bytes()))))
+            .set((hash.eq(Some(&hash_.bytes)),
+                  tree_ref.eq(Some(tree_ref_.as_bytes())),
+                  size.eq(Some(size_ as i64))))
             .execute(&self.conn)
             .expect("Error updating snapshot");
     }
 
-    /// Update existing snapshot.
-    pub fn update(&mut self, snapshot: &Info, hash: &hash::Hash, tree_ref: &blob::ChunkRef) {
-        self.update_internal(snapshot, "anonymous", hash, tree_ref);
-    }
-
     fn set_tag(&mut self, snapshot_: &Info, tag_: tags::Tag) {
         use self::schema::snapshots::dsl::*;
 
@@ -263,7 +297,7 @@ impl SnapshotIndex {
     }
 
     /// Extract latest snapshot data for family.
-    pub fn latest(&mut self, family: &str) -> Option<(Info, hash::Hash, Option<blob::ChunkRef>)> {
+    pub fn latest(&mut self, family: &str) -> Option<Status> {
         let family_id_opt = self.get_family_id(&family);
         family_id_opt.and_then(|family_id_| {
             use self::schema::snapshots::dsl::*;
@@ -274,15 +308,7 @@ impl SnapshotIndex {
                 .optional()
                 .expect("Error reading latest snapshot");
 
-            row_opt.map(|snap| {
-                (Info {
-                    unique_id: snap.id,
-                    family_id: snap.family_id,
-                    snapshot_id: snap.snapshot_id,
-                },
-                 ::hash::Hash { bytes: snap.hash.unwrap().to_vec() },
-                 ::blob::ChunkRef::from_bytes(&mut &snap.tree_ref.unwrap()[..]).ok())
-            })
+            row_opt.map(|snap| to_status(snap, family.to_owned()))
         })
     }
 
@@ -303,31 +329,7 @@ impl SnapshotIndex {
             }
             .unwrap();
 
-        rows.into_iter()
-            .map(|(snap, fam)| {
-                let status = tags::tag_from_num(snap.tag as i64)
-                    .map_or(WorkStatus::CommitComplete, tag_to_work_status);
-                let hash_ = snap.hash.and_then(|bytes| {
-                    if bytes.is_empty() {
-                        None
-                    } else {
-                        Some(::hash::Hash { bytes: bytes })
-                    }
-                });
-                Status {
-                    family_name: fam.name,
-                    msg: snap.msg,
-                    hash: hash_,
-                    tree_ref: snap.tree_ref,
-                    status: status,
-                    info: Info {
-                        unique_id: snap.id,
-                        snapshot_id: snap.snapshot_id,
-                        family_id: fam.id,
-                    },
-                }
-            })
-            .collect()
+        rows.into_iter().map(|(snap, fam)| to_status(snap, fam.name)).collect()
     }
 
     /// List incomplete snapshots (either committing or deleting).
@@ -344,14 +346,19 @@ impl SnapshotIndex {
     pub fn recover(&mut self,
                    snapshot_id_: i64,
                    family: &str,
-                   msg_: &str,
+                   description: &Description,
+                   created_: Option<i64>,
                    hash_: &[u8],
                    tree_ref_: &blob::ChunkRef,
                    work_opt_: Option<WorkStatus>) {
         let family_id_ = self.get_or_create_family_id(&family);
         let insert = match self.lookup(family, snapshot_id_) {
-            Some((_info, h, r)) => {
-                if h.bytes != hash_ || r.is_none() || &r.unwrap() != tree_ref_ {
+            Some(snap) => {
+                let same_hash = snap.hash.map_or(false, |h| h.bytes == hash_);
+                let same_ref = snap.tree_ref
+                    .and_then(|r| blob::ChunkRef::from_bytes(&mut &r[..]).ok())
+                    .map_or(false, |r| &r == tree_ref_);
+                if !same_hash || !same_ref {
                     panic!("Snapshot already exists, but with different hash");
                 }
                 false
@@ -365,10 +372,14 @@ impl SnapshotIndex {
             let new = self::schema::NewSnapshot {
                 family_id: family_id_,
                 snapshot_id: snapshot_id_,
-                msg: Some(msg_),
+                msg: description.msg.as_ref().map(|m| &m[..]),
                 hash: Some(hash_),
                 tree_ref: Some(&tree_bytes[..]),
                 tag: work_opt_.map_or(tags::Tag::Done, work_status_to_tag) as i32,
+                created: created_,
+                size: None,
+                host: description.host.as_ref().map(|h| &h[..]),
+                path: description.path.as_ref().map(|p| &p[..]),
             };
 
             diesel::insert(&new)
@@ -33,12 +33,16 @@ table! {
         msg -> Nullable<VarChar>,
         hash -> Nullable<Binary>,
         tree_ref -> Nullable<Binary>,
+        created -> Nullable<BigInt>,
+        size -> Nullable<BigInt>,
+        host -> Nullable<VarChar>,
+        path -> Nullable<VarChar>,
     }
 }
 
 joinable!(snapshots -> family (family_id));
 select_column_workaround!(snapshots -> family (id, tag, family_id, snapshot_id, msg,
-                                               hash, tree_ref));
+                                               hash, tree_ref, created, size, host, path));
 select_column_workaround!(family -> snapshots (id, name));
 
 
@@ -65,6 +69,10 @@ pub struct Snapshot {
     pub msg: Option<String>,
     pub hash: Option<Vec<u8>>,
     pub tree_ref: Option<Vec<u8>>,
+    pub created: Option<i64>,
+    pub size: Option<i64>,
+    pub host: Option<String>,
+    pub path: Option<String>,
 }
 
 #[insertable_into(snapshots)]
@@ -75,4 +83,8 @@ pub struct NewSnapshot<'a> {
     pub msg: Option<&'a str>,
     pub hash: Option<&'a [u8]>,
     pub tree_ref: Option<&'a [u8]>,
+    pub created: Option<i64>,
+    pub size: Option<i64>,
+    pub host: Option<&'a str>,
+    pub path: Option<&'a str>,
 }
@@ -0,0 +1,248 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content-defined chunking.
+//!
+//! Cutting a stream at fixed offsets means that inserting a single byte changes every chunk after
+//! it. Instead, we cut where a rolling hash of the last bytes matches a pattern (FastCDC), so
+//! boundaries move along with the content and only chunks around an edit change.
+
+use std::cmp;
+use std::io::{self, Read};
+use std::mem;
+
+
+/// Seed for the gear table. Changing it moves every chunk boundary, which breaks deduplication
+/// against existing snapshots.
+const GEAR_SEED: u64 = 0x6861_7462_6163_6b75;
+
+fn gear_table() -> Vec<u64> {
+    // SplitMix64, so that the table is the same on every run and platform.
+    let mut state = GEAR_SEED;
+    (0..256)
+        .map(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+        .collect()
+}
+
+/// Mask with the `bits` highest bits set. The gear hash shifts left for every byte, so its high
+/// bits depend on the most bytes.
+fn high_bits(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else {
+        !0u64 << (64 - bits)
+    }
+}
+
+pub struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+
+    gear: Vec<u64>,
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    // Harder to match before the average size, easier after (normalized chunking).
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl<R: Read> Chunker<R> {
+    /// Create a chunker producing chunks of `min_size` to `max_size` bytes, and `avg_size` bytes
+    /// on average. `avg_size` must be a power of two.
+    pub fn new(reader: R, min_size: usize, avg_size: usize, max_size: usize) -> Chunker<R> {
+        assert!(0 < min_size && min_size < avg_size && avg_size < max_size);
+        assert!(avg_size.is_power_of_two());
+
+        let bits = avg_size.trailing_zeros();
+        Chunker {
+            reader: reader,
+            buf: Vec::with_capacity(max_size),
+            eof: false,
+            gear: gear_table(),
+            min_size: min_size,
+            avg_size: avg_size,
+            max_size: max_size,
+            mask_small: high_bits(bits + 1),
+            mask_large: high_bits(bits - 1),
+        }
+    }
+
+    /// Read until the buffer holds a full chunk or the reader is exhausted.
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() < self.max_size {
+            let len = self.buf.len();
+            self.buf.resize(self.max_size, 0);
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.buf.truncate(len);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(len + n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the first chunk in `data`.
+    fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = cmp::min(data.len(), self.max_size);
+        let normal = cmp::min(end, self.avg_size);
+
+        let mut hash = 0u64;
+        for i in self.min_size..end {
+            hash = (hash << 1).wrapping_add(self.gear[data[i] as usize]);
+            let mask = if i < normal {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    /// Returns the next chunk. After a read error, which is returned once, the iteration ends.
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if let Err(e) = self.fill() {
+            self.eof = true;
+            self.buf.clear();
+            return Some(Err(e));
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let len = self.cut_point(&self.buf[..]);
+        let rest = self.buf.split_off(len);
+        Some(Ok(mem::replace(&mut self.buf, rest)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cmp;
+    use std::collections::HashSet;
+    use std::io::{self, Cursor, Read};
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        rng.gen_iter().take(len).collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        Chunker::new(Cursor::new(data.to_vec()), 256, 1024, 4096).map(|c| c.unwrap()).collect()
+    }
+
+    #[test]
+    fn identity() {
+        let data = random_bytes(100000);
+        let pieces = chunks(&data[..]);
+        assert!(pieces.len() > 1);
+        for c in pieces.iter().take(pieces.len() - 1) {
+            assert!(c.len() >= 256 && c.len() <= 4096);
+        }
+        assert_eq!(pieces.concat(), data);
+
+        assert_eq!(Chunker::new(Cursor::new(vec![]), 256, 1024, 4096).count(), 0);
+        assert_eq!(chunks(&[0; 4097]).concat(), vec![0; 4097]);
+    }
+
+    #[test]
+    fn shifted_insert_keeps_most_chunks() {
+        let data = random_bytes(200000);
+        let mut shifted = data.clone();
+        shifted.insert(100, 42);
+        shifted.insert(150000, 42);
+
+        let before: HashSet<Vec<u8>> = chunks(&data[..]).into_iter().collect();
+        let after = chunks(&shifted[..]);
+
+        // Only the chunks around the two inserts should differ.
+        let new = after.iter().filter(|c| !before.contains(*c)).count();
+        assert!(before.len() > 100);
+        assert!(new <= 6, "{} of {} chunks changed", new, after.len());
+    }
+
+    #[test]
+    fn boundaries_are_stable() {
+        let data = random_bytes(50000);
+        let lens: Vec<usize> = chunks(&data[..]).iter().map(|c| c.len()).collect();
+
+        // Reading in small pieces must not move the boundaries.
+        struct Trickle(Cursor<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = cmp::min(buf.len(), 7);
+                self.0.read(&mut buf[..n])
+            }
+        }
+        let trickled: Vec<usize> = Chunker::new(Trickle(Cursor::new(data)), 256, 1024, 4096)
+            .map(|c| c.unwrap().len())
+            .collect();
+        assert_eq!(lens, trickled);
+    }
+
+    #[test]
+    fn read_error_ends_chunks() {
+        // Fails after handing out the first 5000 bytes.
+        struct Failing(Cursor<Vec<u8>>);
+        impl Read for Failing {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match try!(self.0.read(buf)) {
+                    0 => Err(io::Error::new(io::ErrorKind::Other, "disk on fire")),
+                    n => Ok(n),
+                }
+            }
+        }
+        let mut chunker = Chunker::new(Failing(Cursor::new(random_bytes(5000))), 256, 1024, 4096);
+        let mut read = 0;
+        loop {
+            match chunker.next() {
+                Some(Ok(chunk)) => read += chunk.len(),
+                Some(Err(e)) => {
+                    assert_eq!(e.to_string(), "disk on fire");
+                    break;
+                }
+                None => panic!("Read error was not reported"),
+            }
+        }
+        assert!(read <= 5000);
+        assert!(chunker.next().is_none());
+    }
+}
@@ -14,27 +14,59 @@
 
 use std::fs;
 use std::io;
-use std::io::Read;
+#[cfg(test)]
+use std::io::Cursor;
 use std::path::PathBuf;
 
+use util::Chunker;
+
+/// Default chunk sizes used when cutting files.
+pub const MIN_CHUNK_SIZE: usize = 32 * 1024;
+pub const AVG_CHUNK_SIZE: usize = 128 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Sizes of the chunks that files are cut into; see `Chunker::new`. Changing these moves chunk
+/// boundaries, which breaks deduplication against existing snapshots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkSizes {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl Default for ChunkSizes {
+    fn default() -> ChunkSizes {
+        ChunkSizes {
+            min: MIN_CHUNK_SIZE,
+            avg: AVG_CHUNK_SIZE,
+            max: MAX_CHUNK_SIZE,
+        }
+    }
+}
+
 pub enum FileIterator {
-    File(fs::File),
+    File(Chunker<fs::File>),
     #[cfg(test)]
-    Buf(Vec<u8>, usize),
+    Buf(Chunker<Cursor<Vec<u8>>>),
     #[cfg(all(test, feature = "benchmarks"))]
     Iter(Box<Iterator<Item = Vec<u8>> + Send>),
 }
 
 impl FileIterator {
-    pub fn new(path: &PathBuf) -> io::Result<FileIterator> {
+    pub fn new(path: &PathBuf, sizes: &ChunkSizes) -> io::Result<FileIterator> {
         match fs::File::open(path) {
-            Ok(f) => Ok(FileIterator::File(f)),
+            Ok(f) => Ok(FileIterator::File(chunker(f, sizes))),
             Err(e) => Err(e),
         }
     }
     #[cfg(test)]
     pub fn from_bytes(contents: Vec<u8>) -> FileIterator {
-        FileIterator::Buf(contents, 0)
+        FileIterator::from_bytes_with_sizes(contents, &ChunkSizes::default())
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes_with_sizes(contents: Vec<u8>, sizes: &ChunkSizes) -> FileIterator {
+        FileIterator::Buf(chunker(Cursor::new(contents), sizes))
     }
 
     #[cfg(all(test, feature = "benchmarks"))]
@@ -45,33 +77,20 @@ impl FileIterator {
     }
 }
 
+fn chunker<R: io::Read>(reader: R, sizes: &ChunkSizes) -> Chunker<R> {
+    Chunker::new(reader, sizes.min, sizes.avg, sizes.max)
+}
+
 impl Iterator for FileIterator {
-    type Item = Vec<u8>;
+    type Item = io::Result<Vec<u8>>;
 
-    fn next(&mut self) -> Option<Vec<u8>> {
-        let chunk_size = 128 * 1024;
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
         match self {
-            &mut FileIterator::File(ref mut f) => {
-                let mut buf = vec![0u8; chunk_size];
-                match f.read(&mut buf[..]) {
-                    Err(_) => None,
-                    Ok(size) if size == 0 => None,
-                    Ok(size) => Some(buf[..size].to_vec()),
-                }
-            }
+            &mut FileIterator::File(ref mut chunker) => chunker.next(),
             #[cfg(test)]
-            &mut FileIterator::Buf(ref vec, ref mut pos) => {
-                use std::cmp;
-                if *pos >= vec.len() {
-                    None
-                } else {
-                    let next = &vec[*pos..cmp::min(*pos + chunk_size, vec.len())];
-                    *pos += chunk_size;
-                    Some(next.to_owned())
-                }
-            }
+            &mut FileIterator::Buf(ref mut chunker) => chunker.next(),
             #[cfg(all(test, feature = "benchmarks"))]
-            &mut FileIterator::Iter(ref mut inner) => inner.next(),
+            &mut FileIterator::Iter(ref mut inner) => inner.next().map(Ok),
         }
     }
 }
@@ -0,0 +1,103 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+/// A shell-style pattern for paths separated by `/`. `?` matches one character and `*` any
+/// number of characters, both except `/`; `**` also matches across directories.
+///
+/// Patterns without a `/` match the last component of a path only, so that `*.txt` matches
+/// text files in every directory. Other patterns match the whole path.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: Vec<char>,
+    name_only: bool,
+}
+
+fn match_here(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(&'*') if pattern.get(1) == Some(&'*') => {
+            // `**/` also matches no directory at all.
+            (pattern.get(2) == Some(&'/') && match_here(&pattern[3..], text)) ||
+            (0..text.len() + 1).any(|i| match_here(&pattern[2..], &text[i..]))
+        }
+        Some(&'*') => {
+            for i in 0..text.len() + 1 {
+                if match_here(&pattern[1..], &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some(&'?') => {
+            !text.is_empty() && text[0] != '/' && match_here(&pattern[1..], &text[1..])
+        }
+        Some(&c) => !text.is_empty() && text[0] == c && match_here(&pattern[1..], &text[1..]),
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob {
+            pattern: pattern.trim_left_matches('/').chars().collect(),
+            name_only: !pattern.contains('/'),
+        }
+    }
+
+    /// Whether `path` matches. It is relative to the same directory as the pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = path.trim_left_matches('/');
+        let text = if self.name_only {
+            path.rsplit('/').next().unwrap_or(path)
+        } else {
+            path
+        };
+        match_here(&self.pattern[..], &text.chars().collect::<Vec<char>>()[..])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let glob = Glob::new("*.txt");
+        assert!(glob.matches("a.txt"));
+        assert!(glob.matches("dir/sub/a.txt"));
+        assert!(!glob.matches("a.txt/b"));
+        assert!(!glob.matches("a.rs"));
+
+        let glob = Glob::new("?.rs");
+        assert!(glob.matches("dir/a.rs"));
+        assert!(!glob.matches("ab.rs"));
+    }
+
+    #[test]
+    fn paths() {
+        let glob = Glob::new("/home/*/notes");
+        assert!(glob.matches("home/alice/notes"));
+        assert!(!glob.matches("home/alice/work/notes"));
+        assert!(!glob.matches("notes"));
+
+        let glob = Glob::new("home/**/*.txt");
+        assert!(glob.matches("home/alice/work/a.txt"));
+        assert!(glob.matches("home/a.txt"));
+        assert!(!glob.matches("tmp/a.txt"));
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+
+/// A cache of byte buffers, bounded by their total size. When full, the least recently used
+/// buffers are evicted first.
+pub struct LruCache<K> {
+    entries: BTreeMap<K, (u64, Vec<u8>)>,
+    // Keys ordered by their last use.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl<K: Ord + Clone> LruCache<K> {
+    pub fn new(max_size: usize) -> LruCache<K> {
+        LruCache {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size: max_size,
+        }
+    }
+
+    /// Total size of the cached buffers.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&Vec<u8>> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(&mut (ref mut used, ref value)) => {
+                let k = self.order.remove(&*used).unwrap();
+                *used = self.tick;
+                self.order.insert(self.tick, k);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    /// Insert a buffer, evicting others as needed. Buffers larger than the cache are not kept.
+    pub fn put(&mut self, key: K, value: Vec<u8>) {
+        self.remove(&key);
+        if value.len() > self.max_size {
+            return;
+        }
+
+        while self.size + value.len() > self.max_size {
+            let oldest = *self.order.keys().next().unwrap();
+            let k = self.order.remove(&oldest).unwrap();
+            self.remove(&k);
+        }
+
+        self.tick += 1;
+        self.size += value.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (self.tick, value));
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((used, value)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.size -= value.len();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(10);
+        cache.put(1, vec![0; 4]);
+        cache.put(2, vec![0; 4]);
+        assert_eq!(cache.size(), 8);
+
+        // Using 1 makes 2 the oldest entry.
+        assert!(cache.get(&1).is_some());
+        cache.put(3, vec![0; 4]);
+        assert!(cache.get(&2).is_none());
+        assert!(cache.get(&1).is_some());
+        assert!(cache.get(&3).is_some());
+        assert_eq!(cache.size(), 8);
+
+        // A large entry can evict several others.
+        cache.put(4, vec![0; 9]);
+        assert_eq!(cache.size(), 9);
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&3).is_none());
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut cache = LruCache::new(10);
+        cache.put(1, vec![1; 4]);
+        cache.put(1, vec![2; 6]);
+        assert_eq!(cache.get(&1), Some(&vec![2; 6]));
+        assert_eq!(cache.size(), 6);
+
+        // Too large to cache at all.
+        cache.put(2, vec![0; 11]);
+        assert!(cache.get(&2).is_none());
+        assert_eq!(cache.size(), 6);
+
+        cache.remove(&1);
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.size(), 0);
+    }
+}
@@ -12,21 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod chunker;
 mod counter;
 mod file_iterator;
 mod fnbox;
+mod glob;
 mod infowriter;
 mod listdir;
+mod lru_cache;
 mod ordered_collection;
 mod periodic_timer;
 mod process;
 mod unique_priority_queue;
 
+pub use self::chunker::Chunker;
 pub use self::counter::Counter;
-pub use self::file_iterator::FileIterator;
+pub use self::file_iterator::{ChunkSizes, FileIterator};
 pub use self::fnbox::FnBox;
+pub use self::glob::Glob;
 pub use self::infowriter::InfoWriter;
 pub use self::listdir::{HasPath, PathHandler};
+pub use self::lru_cache::LruCache;
 pub use self::periodic_timer::PeriodicTimer;
 pub use self::process::{MsgHandler, Process};
 pub use self::unique_priority_queue::UniquePriorityQueue;
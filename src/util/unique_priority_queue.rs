@@ -69,6 +69,13 @@ impl<P: Clone + Ord, K: Clone + Ord, V> UniquePriorityQueue<P, K, V> {
         cur.0 = Status::Ready;
     }
 
+    pub fn remove(&mut self, k: &K) -> Option<V> {
+        self.key_to_priority
+            .remove(k)
+            .and_then(|prio| self.priority.remove(&prio))
+            .map(|(_status, _k, v)| v)
+    }
+
     pub fn pop_min_if_complete(&mut self) -> Option<(P, K, V)> {
         let min_opt = self.priority
             .pop_min_when(|_k, min| min.0 == Status::Ready);